@@ -0,0 +1,90 @@
+//! C命令の各ニーモニックを機械語のビット列に変換する
+
+/// destのビット列(d1d2d3)。A、D、Mの並び順は問わない
+pub fn dest(mnemonic: &str) -> Option<u16> {
+    if mnemonic.is_empty() {
+        return None;
+    }
+    let mut bits = 0;
+    for register in mnemonic.chars() {
+        let bit = match register {
+            'A' => 0b100,
+            'D' => 0b010,
+            'M' => 0b001,
+            _ => return None,
+        };
+        // 同じレジスタを2回書いたものは受け付けない
+        if bits & bit != 0 {
+            return None;
+        }
+        bits |= bit;
+    }
+    Some(bits)
+}
+
+/// compのビット列(a c1~c6)。交換できる演算は`A+D`のように左右を入れ替えた形も受け付ける
+pub fn comp(mnemonic: &str) -> Option<u16> {
+    Some(match mnemonic {
+        "0" => 0b0101010,
+        "1" => 0b0111111,
+        "-1" => 0b0111010,
+        "D" => 0b0001100,
+        "A" => 0b0110000,
+        "M" => 0b1110000,
+        "!D" => 0b0001101,
+        "!A" => 0b0110001,
+        "!M" => 0b1110001,
+        "-D" => 0b0001111,
+        "-A" => 0b0110011,
+        "-M" => 0b1110011,
+        "D+1" => 0b0011111,
+        "A+1" => 0b0110111,
+        "M+1" => 0b1110111,
+        "D-1" => 0b0001110,
+        "A-1" => 0b0110010,
+        "M-1" => 0b1110010,
+        "D+A" | "A+D" => 0b0000010,
+        "D+M" | "M+D" => 0b1000010,
+        "D-A" => 0b0010011,
+        "D-M" => 0b1010011,
+        "A-D" => 0b0000111,
+        "M-D" => 0b1000111,
+        "D&A" | "A&D" => 0b0000000,
+        "D&M" | "M&D" => 0b1000000,
+        "D|A" | "A|D" => 0b0010101,
+        "D|M" | "M|D" => 0b1010101,
+        _ => return None,
+    })
+}
+
+/// jumpのビット列(j1j2j3)
+pub fn jump(mnemonic: &str) -> Option<u16> {
+    Some(match mnemonic {
+        "JGT" => 0b001,
+        "JEQ" => 0b010,
+        "JGE" => 0b011,
+        "JLT" => 0b100,
+        "JNE" => 0b101,
+        "JLE" => 0b110,
+        "JMP" => 0b111,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mnemonics() {
+        assert_eq!(dest("AMD"), Some(0b111));
+        assert_eq!(dest("MD"), dest("DM"));
+        assert_eq!(dest("MM"), None);
+        assert_eq!(dest(""), None);
+        assert_eq!(comp("D|M"), Some(0b1010101));
+        assert_eq!(comp("A+D"), comp("D+A"));
+        assert_eq!(comp("D+2"), None);
+        assert_eq!(jump("JMP"), Some(0b111));
+        assert_eq!(jump("jmp"), None);
+    }
+}
@@ -0,0 +1,39 @@
+use std::fmt::{self, Display};
+
+pub type AssembleResult<T> = std::result::Result<T, AssembleError>;
+
+/// アセンブルに失敗した行と理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    /// 1始まりの行番号
+    pub line: usize,
+    pub message: String,
+}
+
+impl AssembleError {
+    pub fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<asm>:{}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let error = AssembleError::new(12, "unknown comp `D+2`");
+        assert_eq!(error.to_string(), "<asm>:12: unknown comp `D+2`");
+    }
+}
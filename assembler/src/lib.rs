@@ -0,0 +1,380 @@
+//! Hackアセンブリを機械語に変換するアセンブラ。
+//! 1回目の走査でラベルにROMアドレスを割り当て、2回目で命令を変換し、未定義のシンボルを変数としてRAM[16]から割り当てる
+pub mod code;
+pub mod error;
+pub mod symbol_table;
+
+use std::io::{BufRead, BufReader};
+
+pub use error::{AssembleError, AssembleResult};
+pub use symbol_table::SymbolTable;
+
+const COMMENT_OUT_TOKEN: &str = "//";
+/// A命令で指定できる値の上限。最上位ビットはC命令との区別に使う
+const MAX_A_VALUE: u16 = 0x7fff;
+/// C命令の先頭3ビット
+const C_INSTRUCTION_PREFIX: u16 = 0b111 << 13;
+
+/// アセンブルした結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// ROMに配置する命令列
+    pub words: Vec<u16>,
+    /// プログラムで定義したラベルと割り当てた変数
+    pub symbols: SymbolTable,
+}
+
+impl Program {
+    /// 1行に16桁の2進数を書いた.hackファイルの形式にする
+    pub fn to_hack(&self) -> String {
+        self.words
+            .iter()
+            .map(|word| format!("{:016b}\n", word))
+            .collect()
+    }
+}
+
+/// アセンブリ1行分を解釈した命令
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Instruction {
+    /// `@value`または`@symbol`
+    A(String),
+    /// `dest=comp;jump`
+    C {
+        dest: Option<String>,
+        comp: String,
+        jump: Option<String>,
+    },
+    /// `(label)`
+    L(String),
+}
+
+pub struct Parser {
+    asm_code: Box<dyn BufRead>,
+    line_number: usize,
+    current_instruction: Option<Instruction>,
+}
+
+impl Parser {
+    pub fn new<T: BufRead + 'static>(asm_file: T) -> Self {
+        Self {
+            asm_code: Box::new(BufReader::new(asm_file)),
+            line_number: 0,
+            current_instruction: None,
+        }
+    }
+
+    pub fn has_more_lines(&mut self) -> AssembleResult<bool> {
+        match self.asm_code.fill_buf() {
+            Ok(buf) => Ok(!buf.is_empty()),
+            Err(e) => Err(AssembleError::new(self.line_number + 1, e.to_string())),
+        }
+    }
+
+    pub fn advance(&mut self) -> AssembleResult<()> {
+        // コメントと空白だけの行を無視して次の行を読み込む
+        self.current_instruction = None;
+        while self.has_more_lines()? {
+            self.line_number += 1;
+            let mut line = String::new();
+            if let Err(e) = self.asm_code.read_line(&mut line) {
+                return Err(AssembleError::new(self.line_number, e.to_string()));
+            }
+            let line = match line.find(COMMENT_OUT_TOKEN) {
+                Some(comment_start) => &line[..comment_start],
+                None => &line,
+            };
+            let code = line.split_whitespace().collect::<String>();
+            if code.is_empty() {
+                continue;
+            }
+            self.current_instruction = Some(self.parse_instruction(&code)?);
+            break;
+        }
+        Ok(())
+    }
+
+    /// 現在の命令。advanceで読み進めた結果、命令が残っていなければNone
+    pub fn instruction(&self) -> Option<&Instruction> {
+        self.current_instruction.as_ref()
+    }
+
+    /// 現在の命令の1始まりの行番号
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    fn parse_instruction(&self, code: &str) -> AssembleResult<Instruction> {
+        if let Some(symbol) = code.strip_prefix('@') {
+            if !is_number(symbol) {
+                self.check_symbol(symbol)?;
+            } else if symbol
+                .parse::<u16>()
+                .map_or(true, |value| value > MAX_A_VALUE)
+            {
+                return Err(self.error(format!(
+                    "expected a number between 0 and {}, found `{}`",
+                    MAX_A_VALUE, symbol
+                )));
+            }
+            return Ok(Instruction::A(symbol.to_string()));
+        }
+        if let Some(label) = code.strip_prefix('(') {
+            let label = label
+                .strip_suffix(')')
+                .ok_or_else(|| self.error(format!("missing `)` in `{}`", code)))?;
+            self.check_symbol(label)?;
+            return Ok(Instruction::L(label.to_string()));
+        }
+
+        let (dest, rest) = match code.split_once('=') {
+            Some((dest, rest)) => (Some(dest), rest),
+            None => (None, code),
+        };
+        let (comp, jump) = match rest.split_once(';') {
+            Some((comp, jump)) => (comp, Some(jump)),
+            None => (rest, None),
+        };
+        if let Some(dest) = dest.filter(|dest| code::dest(dest).is_none()) {
+            return Err(self.error(format!("unknown dest `{}`", dest)));
+        }
+        if code::comp(comp).is_none() {
+            return Err(self.error(format!("unknown comp `{}`", comp)));
+        }
+        if let Some(jump) = jump.filter(|jump| code::jump(jump).is_none()) {
+            return Err(self.error(format!("unknown jump `{}`", jump)));
+        }
+        Ok(Instruction::C {
+            dest: dest.map(str::to_string),
+            comp: comp.to_string(),
+            jump: jump.map(str::to_string),
+        })
+    }
+
+    /// シンボルは英数字と`_.$:`からなり、数字で始まらない
+    fn check_symbol(&self, symbol: &str) -> AssembleResult<()> {
+        let is_symbol_char = |c: char| c.is_ascii_alphanumeric() || "_.$:".contains(c);
+        match symbol.chars().next() {
+            None => Err(self.error("missing symbol")),
+            Some(c) if c.is_ascii_digit() => {
+                Err(self.error(format!("invalid symbol `{}`", symbol)))
+            }
+            _ if !symbol.chars().all(is_symbol_char) => {
+                Err(self.error(format!("invalid symbol `{}`", symbol)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> AssembleError {
+        AssembleError::new(self.line_number, message)
+    }
+}
+
+fn is_number(symbol: &str) -> bool {
+    !symbol.is_empty() && symbol.chars().all(|c| c.is_ascii_digit())
+}
+
+/// アセンブリを機械語の命令列に変換する
+pub fn assemble(asm_code: &str) -> AssembleResult<Program> {
+    let mut parser = Parser::new(std::io::Cursor::new(asm_code.to_string()));
+    let mut instructions = Vec::new();
+    while parser.has_more_lines()? {
+        parser.advance()?;
+        if let Some(instruction) = parser.instruction() {
+            instructions.push((parser.line_number(), instruction.clone()));
+        }
+    }
+
+    // 1回目: ラベルに次の命令のROMアドレスを割り当てる
+    let mut symbol_table = SymbolTable::new();
+    let mut rom_address: u16 = 0;
+    for (line, instruction) in &instructions {
+        match instruction {
+            Instruction::L(label) if symbol_table.contains(label) => {
+                return Err(AssembleError::new(
+                    *line,
+                    format!("duplicate label `{}`", label),
+                ));
+            }
+            Instruction::L(label) => symbol_table.add_label(label, rom_address),
+            _ => {
+                rom_address = rom_address
+                    .checked_add(1)
+                    .filter(|&address| address <= MAX_A_VALUE + 1)
+                    .ok_or_else(|| AssembleError::new(*line, "program does not fit in ROM"))?;
+            }
+        }
+    }
+
+    // 2回目: 命令を変換する
+    let mut words = Vec::new();
+    for (line, instruction) in instructions {
+        match instruction {
+            Instruction::A(symbol) => {
+                let address = match symbol.parse() {
+                    Ok(value) => value,
+                    Err(_) => match symbol_table.get_address(&symbol) {
+                        Some(address) => address,
+                        None => symbol_table.add_variable(&symbol).ok_or_else(|| {
+                            AssembleError::new(
+                                line,
+                                format!("too many variables to allocate `{}`", symbol),
+                            )
+                        })?,
+                    },
+                };
+                words.push(address);
+            }
+            Instruction::C { dest, comp, jump } => {
+                // 各ニーモニックは解析時に検査済み
+                let dest = dest.map_or(0, |dest| code::dest(&dest).unwrap());
+                let jump = jump.map_or(0, |jump| code::jump(&jump).unwrap());
+                let comp = code::comp(&comp).unwrap();
+                words.push(C_INSTRUCTION_PREFIX | comp << 6 | dest << 3 | jump);
+            }
+            Instruction::L(_) => (),
+        }
+    }
+    Ok(Program {
+        words,
+        symbols: symbol_table,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_parser() -> Result<()> {
+        let asm_code = "
+            // コメント行
+            @Main.0 // 行末コメント
+            AM = M - 1
+            (LOOP)
+            D;JGT
+            ";
+        let mut parser = Parser::new(Cursor::new(asm_code));
+        let mut instructions = Vec::new();
+        while parser.has_more_lines()? {
+            parser.advance()?;
+            if let Some(instruction) = parser.instruction() {
+                instructions.push((parser.line_number(), instruction.clone()));
+            }
+        }
+        assert_eq!(
+            instructions,
+            vec![
+                (3, Instruction::A("Main.0".to_string())),
+                (
+                    4,
+                    Instruction::C {
+                        dest: Some("AM".to_string()),
+                        comp: "M-1".to_string(),
+                        jump: None
+                    }
+                ),
+                (5, Instruction::L("LOOP".to_string())),
+                (
+                    6,
+                    Instruction::C {
+                        dest: None,
+                        comp: "D".to_string(),
+                        jump: Some("JGT".to_string())
+                    }
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_add() -> Result<()> {
+        let hack = assemble("@2\nD=A\n@3\nD=D+A\n@0\nM=D\n")?.to_hack();
+        let expect = "\
+0000000000000010
+1110110000010000
+0000000000000011
+1110000010010000
+0000000000000000
+1110001100001000
+";
+        assert_eq!(hack, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_symbols() -> Result<()> {
+        let asm_code = "
+            @i
+            M=1
+            (LOOP)
+            @i
+            D=M
+            @LOOP
+            D;JGT
+            @sum
+            @SCREEN
+            @R15
+            @i
+            ";
+        let program = assemble(asm_code)?;
+        assert_eq!(
+            program.words,
+            vec![
+                16,
+                0b1110111111001000,
+                16,
+                0b1111110000010000,
+                2,
+                0b1110001100000001,
+                17,
+                16384,
+                15,
+                16,
+            ]
+        );
+        assert_eq!(program.symbols.labels(), [("LOOP".to_string(), 2)]);
+        assert_eq!(
+            program.symbols.variables(),
+            [("i".to_string(), 16), ("sum".to_string(), 17)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        for (asm_code, message) in [
+            ("D=D+2", "<asm>:1: unknown comp `D+2`"),
+            ("@1\nX=D", "<asm>:2: unknown dest `X`"),
+            ("0;JMPS", "<asm>:1: unknown jump `JMPS`"),
+            (
+                "@32768",
+                "<asm>:1: expected a number between 0 and 32767, found `32768`",
+            ),
+            ("@", "<asm>:1: missing symbol"),
+            ("@1abc", "<asm>:1: invalid symbol `1abc`"),
+            ("(LOOP", "<asm>:1: missing `)` in `(LOOP`"),
+            ("(LOOP)\n@LOOP\n(LOOP)", "<asm>:3: duplicate label `LOOP`"),
+            ("(R0)", "<asm>:1: duplicate label `R0`"),
+        ] {
+            assert_eq!(assemble(asm_code).unwrap_err().to_string(), message);
+        }
+
+        // 変数はRAM[16~255]の240個まで。それ以上はスタックを壊すのでエラーにする
+        let variables = |count: usize| -> String {
+            (0..count)
+                .map(|index| format!("@Main.{}\nM=0\n", index))
+                .collect()
+        };
+        assert!(assemble(&variables(240)).is_ok());
+        assert_eq!(
+            assemble(&variables(241)).unwrap_err().to_string(),
+            "<asm>:481: too many variables to allocate `Main.240`"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+/// 変数に割り当てるRAMの先頭。RAM[0~15]はR0~R15として予約されている
+pub const VARIABLE_BASE_ADDRESS: u16 = 16;
+/// 変数に割り当てられるRAMの終わり(この値を含まない)。RAM[256]からはスタックに使う
+pub const VARIABLE_END_ADDRESS: u16 = 256;
+
+const PREDEFINED_SYMBOLS: [(&str, u16); 7] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+/// シンボルとアドレスの対応。定義済みシンボルを含んだ状態で作られる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: HashMap<String, u16>,
+    // 定義済みシンボルを除いた、プログラムで定義されたラベルと変数。追加された順に持つ
+    labels: Vec<(String, u16)>,
+    variables: Vec<(String, u16)>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        let mut symbols = PREDEFINED_SYMBOLS
+            .iter()
+            .map(|&(symbol, address)| (symbol.to_string(), address))
+            .collect::<HashMap<_, _>>();
+        for register in 0..16 {
+            symbols.insert(format!("R{}", register), register);
+        }
+        Self {
+            symbols,
+            labels: Vec::new(),
+            variables: Vec::new(),
+        }
+    }
+
+    /// ラベルにROMアドレスを割り当てる
+    pub fn add_label(&mut self, symbol: &str, address: u16) {
+        self.symbols.insert(symbol.to_string(), address);
+        self.labels.push((symbol.to_string(), address));
+    }
+
+    pub fn contains(&self, symbol: &str) -> bool {
+        self.symbols.contains_key(symbol)
+    }
+
+    pub fn get_address(&self, symbol: &str) -> Option<u16> {
+        self.symbols.get(symbol).copied()
+    }
+
+    /// 未定義のシンボルを変数として次の空きアドレスに割り当てる。
+    /// 空きアドレスがスタックの領域に入る場合は割り当てずにNoneを返す
+    pub fn add_variable(&mut self, symbol: &str) -> Option<u16> {
+        let address = VARIABLE_BASE_ADDRESS as usize + self.variables.len();
+        if address >= VARIABLE_END_ADDRESS as usize {
+            return None;
+        }
+        let address = address as u16;
+        self.symbols.insert(symbol.to_string(), address);
+        self.variables.push((symbol.to_string(), address));
+        Some(address)
+    }
+
+    /// ラベルとそのROMアドレス。ラベルは出現順に定義されるのでアドレスの昇順に並ぶ
+    pub fn labels(&self) -> &[(String, u16)] {
+        &self.labels
+    }
+
+    /// 変数とそのRAMアドレス。割り当てた順なのでアドレスの昇順に並ぶ
+    pub fn variables(&self) -> &[(String, u16)] {
+        &self.variables
+    }
+
+    /// ラベルのROMアドレスと変数のRAMアドレスの一覧をJSONにする。
+    /// シンボルに使える文字にはJSONでエスケープが必要なものが無いのでそのまま書く
+    pub fn to_json(&self) -> String {
+        let entries = |symbols: &[(String, u16)], key: &str| -> String {
+            if symbols.is_empty() {
+                return "[]".to_string();
+            }
+            let entries = symbols
+                .iter()
+                .map(|(name, address)| {
+                    format!("    {{\"name\": \"{}\", \"{}\": {}}}", name, key, address)
+                })
+                .collect::<Vec<_>>();
+            format!("[\n{}\n  ]", entries.join(",\n"))
+        };
+        format!(
+            "{{\n  \"labels\": {},\n  \"variables\": {}\n}}\n",
+            entries(&self.labels, "rom"),
+            entries(&self.variables, "ram")
+        )
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut symbol_table = SymbolTable::new();
+        assert_eq!(symbol_table.get_address("R15"), Some(15));
+        assert_eq!(symbol_table.get_address("KBD"), Some(24576));
+        assert!(!symbol_table.contains("i"));
+
+        assert_eq!(symbol_table.add_variable("i"), Some(16));
+        assert_eq!(symbol_table.add_variable("sum"), Some(17));
+        assert_eq!(symbol_table.get_address("i"), Some(16));
+
+        for index in 2..240 {
+            assert!(symbol_table.add_variable(&format!("v{}", index)).is_some());
+        }
+        assert_eq!(symbol_table.get_address("v239"), Some(255));
+        assert_eq!(symbol_table.add_variable("overflow"), None);
+        assert!(!symbol_table.contains("overflow"));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut symbol_table = SymbolTable::new();
+        symbol_table.add_label("Sys.init", 3);
+        symbol_table.add_label("Sys.init$ret.0", 9);
+        symbol_table.add_variable("Main.0");
+        let expect = r#"{
+  "labels": [
+    {"name": "Sys.init", "rom": 3},
+    {"name": "Sys.init$ret.0", "rom": 9}
+  ],
+  "variables": [
+    {"name": "Main.0", "ram": 16}
+  ]
+}
+"#;
+        assert_eq!(symbol_table.to_json(), expect);
+
+        assert_eq!(
+            SymbolTable::new().to_json(),
+            "{\n  \"labels\": [],\n  \"variables\": []\n}\n"
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Hackアセンブリの命令を表す型。CodeWriterは命令列を組み立て、最後にrenderでまとめてテキストにする
+use std::{fmt, str::FromStr};
+
+use strum_macros::{AsRefStr, EnumString};
+
+/// A命令(`@xxx`)のオペランド
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Value(u16),
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+pub enum Dest {
+    M,
+    D,
+    MD,
+    A,
+    AM,
+    AD,
+    AMD,
+}
+
+impl Dest {
+    pub fn writes_a(self) -> bool {
+        self.as_ref().contains('A')
+    }
+
+    pub fn writes_d(self) -> bool {
+        self.as_ref().contains('D')
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+pub enum Comp {
+    #[strum(serialize = "0")]
+    Zero,
+    #[strum(serialize = "1")]
+    One,
+    #[strum(serialize = "-1")]
+    MinusOne,
+    D,
+    A,
+    M,
+    #[strum(serialize = "!D")]
+    NotD,
+    #[strum(serialize = "!A")]
+    NotA,
+    #[strum(serialize = "!M")]
+    NotM,
+    #[strum(serialize = "-D")]
+    NegD,
+    #[strum(serialize = "-A")]
+    NegA,
+    #[strum(serialize = "-M")]
+    NegM,
+    #[strum(serialize = "D+1")]
+    DPlusOne,
+    #[strum(serialize = "A+1")]
+    APlusOne,
+    #[strum(serialize = "M+1")]
+    MPlusOne,
+    #[strum(serialize = "D-1")]
+    DMinusOne,
+    #[strum(serialize = "A-1")]
+    AMinusOne,
+    #[strum(serialize = "M-1")]
+    MMinusOne,
+    #[strum(to_string = "D+A", serialize = "A+D")]
+    DPlusA,
+    #[strum(to_string = "D+M", serialize = "M+D")]
+    DPlusM,
+    #[strum(serialize = "D-A")]
+    DMinusA,
+    #[strum(serialize = "D-M")]
+    DMinusM,
+    #[strum(serialize = "A-D")]
+    AMinusD,
+    #[strum(serialize = "M-D")]
+    MMinusD,
+    #[strum(to_string = "D&A", serialize = "A&D")]
+    DAndA,
+    #[strum(to_string = "D&M", serialize = "M&D")]
+    DAndM,
+    #[strum(to_string = "D|A", serialize = "A|D")]
+    DOrA,
+    #[strum(to_string = "D|M", serialize = "M|D")]
+    DOrM,
+}
+
+impl Comp {
+    pub fn reads_d(self) -> bool {
+        self.as_ref().contains('D')
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumString)]
+pub enum Jump {
+    JGT,
+    JEQ,
+    JGE,
+    JLT,
+    JNE,
+    JLE,
+    JMP,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmInstr {
+    A(Address),
+    C {
+        dest: Option<Dest>,
+        comp: Comp,
+        jump: Option<Jump>,
+    },
+    Label(String),
+    Comment(String),
+}
+
+impl AsmInstr {
+    /// `@symbol`
+    pub fn at(symbol: impl Into<String>) -> Self {
+        Self::A(Address::Symbol(symbol.into()))
+    }
+
+    /// `@value`
+    pub fn value(value: u16) -> Self {
+        Self::A(Address::Value(value))
+    }
+
+    /// `dest=comp`
+    pub fn assign(dest: Dest, comp: Comp) -> Self {
+        Self::C {
+            dest: Some(dest),
+            comp,
+            jump: None,
+        }
+    }
+
+    /// `comp;jump`
+    pub fn jump(comp: Comp, jump: Jump) -> Self {
+        Self::C {
+            dest: None,
+            comp,
+            jump: Some(jump),
+        }
+    }
+
+    pub fn label(name: impl Into<String>) -> Self {
+        Self::Label(name.into())
+    }
+
+    pub fn comment(text: impl Into<String>) -> Self {
+        Self::Comment(text.into())
+    }
+
+    /// ROMに配置される命令(A命令とC命令)か
+    pub fn is_instruction(&self) -> bool {
+        matches!(self, Self::A(_) | Self::C { .. })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Value(value) => write!(f, "{}", value),
+            Self::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+impl fmt::Display for AsmInstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A(address) => write!(f, "@{}", address),
+            Self::C { dest, comp, jump } => {
+                if let Some(dest) = dest {
+                    write!(f, "{}=", dest.as_ref())?;
+                }
+                write!(f, "{}", comp.as_ref())?;
+                if let Some(jump) = jump {
+                    write!(f, ";{}", jump.as_ref())?;
+                }
+                Ok(())
+            }
+            Self::Label(name) => write!(f, "({})", name),
+            Self::Comment(text) => write!(f, "// {}", text),
+        }
+    }
+}
+
+/// 1行分のアセンブリを命令として読む。空行は扱わない
+impl FromStr for AsmInstr {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        if let Some(text) = line.strip_prefix("//") {
+            return Ok(Self::comment(text.trim()));
+        }
+        if let Some(name) = line.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            return Ok(Self::label(name));
+        }
+        if let Some(symbol) = line.strip_prefix('@') {
+            return Ok(match symbol.parse() {
+                Ok(value) => Self::value(value),
+                Err(_) => Self::at(symbol),
+            });
+        }
+
+        let (dest, rest) = match line.split_once('=') {
+            Some((dest, rest)) => (Some(dest), rest),
+            None => (None, line),
+        };
+        let (comp, jump) = match rest.split_once(';') {
+            Some((comp, jump)) => (comp, Some(jump)),
+            None => (rest, None),
+        };
+        Ok(Self::C {
+            dest: dest
+                .map(|dest| dest.parse().map_err(|_| format!("invalid dest `{}`", dest)))
+                .transpose()?,
+            comp: comp
+                .parse()
+                .map_err(|_| format!("invalid comp `{}`", comp))?,
+            jump: jump
+                .map(|jump| jump.parse().map_err(|_| format!("invalid jump `{}`", jump)))
+                .transpose()?,
+        })
+    }
+}
+
+/// 命令列を1行1命令のテキストにする
+pub fn render(instrs: &[AsmInstr]) -> String {
+    instrs.iter().map(|instr| format!("{}\n", instr)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let instrs = vec![
+            AsmInstr::comment("push"),
+            AsmInstr::at("SP"),
+            AsmInstr::assign(Dest::AM, Comp::MMinusOne),
+            AsmInstr::value(7),
+            AsmInstr::jump(Comp::D, Jump::JNE),
+            AsmInstr::C {
+                dest: Some(Dest::D),
+                comp: Comp::DPlusA,
+                jump: Some(Jump::JMP),
+            },
+            AsmInstr::label("Main$LOOP"),
+        ];
+        let asm = render(&instrs);
+        assert_eq!(
+            asm,
+            "// push\n@SP\nAM=M-1\n@7\nD;JNE\nD=D+A;JMP\n(Main$LOOP)\n"
+        );
+
+        let parsed = asm
+            .lines()
+            .map(str::parse)
+            .collect::<Result<Vec<AsmInstr>, _>>()
+            .unwrap();
+        assert_eq!(parsed, instrs);
+        assert_eq!("M=A+D".parse(), Ok(AsmInstr::assign(Dest::M, Comp::DPlusA)));
+        assert!("X=D".parse::<AsmInstr>().is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! テスト用のHack CPUエミュレータ。生成したアセンブリを同梱のアセンブラで機械語にして実行し、RAMの状態を検証する
+use anyhow::Result;
+
+const RAM_SIZE: usize = 32768;
+/// C命令の各フィールドの位置
+const A_BIT: u16 = 1 << 12;
+const COMP_SHIFT: u16 = 6;
+const DEST_SHIFT: u16 = 3;
+const C_INSTRUCTION_BIT: u16 = 1 << 15;
+const HALT_INSTRUCTION: u16 = 0b1110_1010_1000_0111; // 0;JMP
+
+pub struct Emulator {
+    pub ram: Vec<i16>,
+    rom: Vec<u16>,
+    pc: usize,
+    a: i16,
+    d: i16,
+}
+
+impl Emulator {
+    pub fn new(asm: &str) -> Result<Self> {
+        Ok(Self {
+            ram: vec![0; RAM_SIZE],
+            rom: assembler::assemble(asm)?.words,
+            pc: 0,
+            a: 0,
+            d: 0,
+        })
+    }
+
+    /// ENDの無限ループに入るかmax_steps命令を実行するまで実行する。停止した場合はtrue
+    pub fn run(&mut self, max_steps: usize) -> bool {
+        for _ in 0..max_steps {
+            if self.pc >= self.rom.len() || self.is_halted() {
+                return true;
+            }
+            self.step();
+        }
+        false
+    }
+
+    // `@n / 0;JMP` で自分自身に飛ぶ無限ループを停止とみなす
+    fn is_halted(&self) -> bool {
+        self.rom[self.pc] as usize == self.pc
+            && self.rom.get(self.pc + 1) == Some(&HALT_INSTRUCTION)
+    }
+
+    fn step(&mut self) {
+        let instruction = self.rom[self.pc];
+        if instruction & C_INSTRUCTION_BIT == 0 {
+            self.a = instruction as i16;
+            self.pc += 1;
+            return;
+        }
+
+        let y = if instruction & A_BIT == 0 {
+            self.a
+        } else {
+            self.ram[self.address()]
+        };
+        let value = alu(self.d, y, instruction >> COMP_SHIFT);
+        let dest = instruction >> DEST_SHIFT;
+        if dest & 0b001 != 0 {
+            let address = self.address();
+            self.ram[address] = value;
+        }
+        if dest & 0b010 != 0 {
+            self.d = value;
+        }
+        let jump_address = self.a as u16 as usize;
+        if dest & 0b100 != 0 {
+            self.a = value;
+        }
+        let jumps = match instruction & 0b111 {
+            0b000 => false,
+            0b001 => value > 0,
+            0b010 => value == 0,
+            0b011 => value >= 0,
+            0b100 => value < 0,
+            0b101 => value != 0,
+            0b110 => value <= 0,
+            _ => true,
+        };
+        self.pc = if jumps { jump_address } else { self.pc + 1 };
+    }
+
+    fn address(&self) -> usize {
+        self.a as u16 as usize % RAM_SIZE
+    }
+}
+
+/// compの下位6ビット(zx nx zy ny f no)に従ってHackのALUと同じ計算をする
+fn alu(x: i16, y: i16, comp: u16) -> i16 {
+    let bit = |n: u16| comp & (1 << (5 - n)) != 0;
+    let x = if bit(0) { 0 } else { x };
+    let x = if bit(1) { !x } else { x };
+    let y = if bit(2) { 0 } else { y };
+    let y = if bit(3) { !y } else { y };
+    let out = if bit(4) { x.wrapping_add(y) } else { x & y };
+    if bit(5) {
+        !out
+    } else {
+        out
+    }
+}
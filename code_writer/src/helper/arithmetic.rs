@@ -1,120 +1,237 @@
-use crate::VariableRegister;
-use anyhow::Result;
-use unindent::unindent;
+use crate::{
+    asm::{AsmInstr, Comp, Dest, Jump},
+    VariableRegister,
+};
+use parser::ArithOp;
 
-const ARITHMETIC_COMMANDS: [&str; 3] = ["add", "sub", "neg"];
-const COMPARISON_COMMANDS: [&str; 3] = ["eq", "gt", "lt"];
-const LOGICAL_COMMANDS: [&str; 3] = ["and", "or", "not"];
+// shared_comparisonsオプションで出力する共通の比較処理の入口。vmのラベルと衝突しないよう`$`を2つ含める
+pub const EQ_ROUTINE: &str = "VM$$EQ";
+pub const GT_ROUTINE: &str = "VM$$GT";
+pub const LT_ROUTINE: &str = "VM$$LT";
+pub const COMPARISON_ROUTINES: [&str; 3] = [EQ_ROUTINE, GT_ROUTINE, LT_ROUTINE];
+const COMPARISON_TRUE: &str = "VM$$CMP_TRUE";
+const COMPARISON_FALSE: &str = "VM$$CMP_FALSE";
 
 pub struct ArithmeticCommandHelper {}
 
 impl ArithmeticCommandHelper {
+    /// Dに左辺(単項演算では未使用)、variable_registerに右辺が入っている状態から結果をDに求める。
+    /// 比較で使うラベルはlabel_prefix(`Main$cmp$3`など)に`.true`などを付けた名前にする
     pub fn get_command(
-        command: &str,
+        command: ArithOp,
         variable_register: &VariableRegister,
-        comparison_count: u16,
-    ) -> Result<String> {
+        label_prefix: &str,
+    ) -> Vec<AsmInstr> {
         match command {
-            cmd if ARITHMETIC_COMMANDS.iter().any(|a_cmd| *a_cmd == cmd) => {
-                Ok(Self::get_arithmetic_command(command, variable_register)?.unwrap())
+            ArithOp::Add | ArithOp::Sub | ArithOp::Neg => {
+                Self::get_arithmetic_command(command, variable_register)
             }
-            cmd if COMPARISON_COMMANDS.iter().any(|c_cmd| *c_cmd == cmd) => Ok(
-                Self::get_comparison_command(command, variable_register, comparison_count)?
-                    .unwrap(),
-            ),
-            cmd if LOGICAL_COMMANDS.iter().any(|l_cmd| *l_cmd == cmd) => {
-                Ok(Self::get_logical_command(command, variable_register)?.unwrap())
+            ArithOp::Eq | ArithOp::Gt | ArithOp::Lt => {
+                Self::get_comparison_command(command, variable_register, label_prefix)
+            }
+            ArithOp::And | ArithOp::Or | ArithOp::Not => {
+                Self::get_logical_command(command, variable_register)
             }
-            cmd => panic!("no support command: {}", cmd),
         }
     }
 
     fn get_arithmetic_command(
-        command: &str,
+        command: ArithOp,
         variable_register: &VariableRegister,
-    ) -> Result<Option<String>> {
-        let operator: Option<&str> = match command {
-            "add" => Some("// add\nD=D+M"),
-            "sub" => Some("// sub\nD=D-M"),
-            "neg" => Some("// neg\nD=-M"),
-            &_ => None,
+    ) -> Vec<AsmInstr> {
+        let comp = match command {
+            ArithOp::Add => Comp::DPlusM,
+            ArithOp::Sub => Comp::DMinusM,
+            _ => Comp::NegM,
         };
 
-        if operator.is_none() {
-            return Ok(None);
-        }
-
-        Ok(Some(unindent(
-            format!("@{}\n{}", variable_register.as_ref(), operator.unwrap(),).as_str(),
-        )))
+        vec![
+            AsmInstr::at(variable_register.as_ref()),
+            AsmInstr::comment(command.as_ref()),
+            AsmInstr::assign(Dest::D, comp),
+        ]
     }
 
     fn get_comparison_command(
-        command: &str,
+        command: ArithOp,
         variable_register: &VariableRegister,
-        comparison_count: u16,
-    ) -> Result<Option<String>> {
-        let comp_operator: Option<&str> = match command {
-            "eq" => Some("// eq\nD;JEQ"),
-            "gt" => Some("// gt\nD;JGT"),
-            "lt" => Some("// lt\nD;JLT"),
-            &_ => None,
+        label_prefix: &str,
+    ) -> Vec<AsmInstr> {
+        let jump = Self::get_comparison_jump(command);
+        let true_label = format!("{}.true", label_prefix);
+        let push_label = format!("{}.push", label_prefix);
+        let difference_code = match command {
+            ArithOp::Eq => vec![
+                AsmInstr::at(variable_register.as_ref()),
+                AsmInstr::assign(Dest::D, Comp::DMinusM),
+            ],
+            _ => [
+                vec![
+                    AsmInstr::at(VariableRegister::R14.as_ref()),
+                    AsmInstr::assign(Dest::M, Comp::D),
+                ],
+                Self::get_signed_difference_code(variable_register, |name| {
+                    format!("{}.{}", label_prefix, name)
+                }),
+            ]
+            .concat(),
+        };
+
+        [
+            difference_code,
+            vec![
+                AsmInstr::at(&true_label),
+                AsmInstr::comment(command.as_ref()),
+                AsmInstr::jump(Comp::D, jump),
+                AsmInstr::assign(Dest::D, Comp::Zero),
+                AsmInstr::at(&push_label),
+                AsmInstr::jump(Comp::Zero, Jump::JMP),
+                AsmInstr::label(true_label),
+                AsmInstr::assign(Dest::D, Comp::MinusOne),
+                AsmInstr::label(push_label),
+            ],
+        ]
+        .concat()
+    }
+
+    /// 共通の比較処理にxをR14、リターンアドレスをR15で渡して飛ぶ。結果はDで返ってくる
+    pub fn get_shared_comparison_command(command: ArithOp, label_prefix: &str) -> Vec<AsmInstr> {
+        let return_label = format!("{}.ret", label_prefix);
+        let routine = match command {
+            ArithOp::Eq => EQ_ROUTINE,
+            ArithOp::Gt => GT_ROUTINE,
+            _ => LT_ROUTINE,
+        };
+
+        vec![
+            AsmInstr::comment(command.as_ref()),
+            AsmInstr::at(VariableRegister::R14.as_ref()),
+            AsmInstr::assign(Dest::M, Comp::D),
+            AsmInstr::at(&return_label),
+            AsmInstr::assign(Dest::D, Comp::A),
+            AsmInstr::at(VariableRegister::R15.as_ref()),
+            AsmInstr::assign(Dest::M, Comp::D),
+            AsmInstr::at(routine),
+            AsmInstr::jump(Comp::Zero, Jump::JMP),
+            AsmInstr::label(return_label),
+        ]
+    }
+
+    /// eq、gt、ltの共通処理。R14にx、variable_registerにyが入った状態で各入口に飛んでくる
+    pub fn get_comparison_routine(variable_register: &VariableRegister) -> Vec<AsmInstr> {
+        let entry_code = |command: ArithOp, routine: &str| -> Vec<AsmInstr> {
+            let difference_code = match command {
+                ArithOp::Eq => vec![
+                    AsmInstr::at(VariableRegister::R14.as_ref()),
+                    AsmInstr::assign(Dest::D, Comp::M),
+                    AsmInstr::at(variable_register.as_ref()),
+                    AsmInstr::assign(Dest::D, Comp::DMinusM),
+                ],
+                _ => Self::get_signed_difference_code(variable_register, |name| {
+                    format!("{}.{}", routine, name)
+                }),
+            };
+            [
+                vec![AsmInstr::label(routine)],
+                difference_code,
+                vec![
+                    AsmInstr::at(COMPARISON_TRUE),
+                    AsmInstr::jump(Comp::D, Self::get_comparison_jump(command)),
+                ],
+            ]
+            .concat()
+        };
+        let return_code = |value: Comp| -> Vec<AsmInstr> {
+            vec![
+                AsmInstr::assign(Dest::D, value),
+                AsmInstr::at(VariableRegister::R15.as_ref()),
+                AsmInstr::assign(Dest::A, Comp::M),
+                AsmInstr::jump(Comp::Zero, Jump::JMP),
+            ]
         };
 
-        if comp_operator.is_none() {
-            return Ok(None);
+        [
+            vec![AsmInstr::comment("comparison routine")],
+            entry_code(ArithOp::Eq, EQ_ROUTINE),
+            vec![
+                AsmInstr::at(COMPARISON_FALSE),
+                AsmInstr::jump(Comp::Zero, Jump::JMP),
+            ],
+            entry_code(ArithOp::Gt, GT_ROUTINE),
+            vec![
+                AsmInstr::at(COMPARISON_FALSE),
+                AsmInstr::jump(Comp::Zero, Jump::JMP),
+            ],
+            // ltの判定で飛ばなかった場合はそのままfalseの処理に進む
+            entry_code(ArithOp::Lt, LT_ROUTINE),
+            vec![AsmInstr::label(COMPARISON_FALSE)],
+            return_code(Comp::Zero),
+            vec![AsmInstr::label(COMPARISON_TRUE)],
+            return_code(Comp::MinusOne),
+        ]
+        .concat()
+    }
+
+    fn get_comparison_jump(command: ArithOp) -> Jump {
+        match command {
+            ArithOp::Eq => Jump::JEQ,
+            ArithOp::Gt => Jump::JGT,
+            _ => Jump::JLT,
         }
+    }
+
+    /// R14のxとvariable_registerのyから、x-yと同じ符号の値をDに求める。
+    /// 符号が異なる場合はx-yがあふれて符号が反転するので、引き算せずにxの符号から決める
+    fn get_signed_difference_code(
+        variable_register: &VariableRegister,
+        label: impl Fn(&str) -> String,
+    ) -> Vec<AsmInstr> {
+        let (y_negative, same_sign, done) = (label("yneg"), label("same"), label("diff"));
+        let x = VariableRegister::R14.as_ref();
 
-        Ok(Some(unindent(
-            format!(
-                r#"
-@{}
-D=D-M
-@TRUE{}
-{}
-D=0
-@PUSH{}
-0;JMP
-(TRUE{})
-D=-1
-(PUSH{})
-"#,
-                variable_register.as_ref(),
-                comparison_count,
-                comp_operator.unwrap(),
-                comparison_count,
-                comparison_count,
-                comparison_count,
-            )
-            .as_str(),
-        )))
+        vec![
+            AsmInstr::at(variable_register.as_ref()),
+            AsmInstr::assign(Dest::D, Comp::M),
+            AsmInstr::at(&y_negative),
+            AsmInstr::jump(Comp::D, Jump::JLT),
+            AsmInstr::at(x),
+            AsmInstr::assign(Dest::D, Comp::M),
+            AsmInstr::at(&same_sign),
+            AsmInstr::jump(Comp::D, Jump::JGE),
+            // x < 0 <= y
+            AsmInstr::assign(Dest::D, Comp::MinusOne),
+            AsmInstr::at(&done),
+            AsmInstr::jump(Comp::Zero, Jump::JMP),
+            AsmInstr::label(y_negative),
+            AsmInstr::at(x),
+            AsmInstr::assign(Dest::D, Comp::M),
+            AsmInstr::at(&same_sign),
+            AsmInstr::jump(Comp::D, Jump::JLT),
+            // y < 0 <= x
+            AsmInstr::assign(Dest::D, Comp::One),
+            AsmInstr::at(&done),
+            AsmInstr::jump(Comp::Zero, Jump::JMP),
+            AsmInstr::label(same_sign),
+            AsmInstr::at(variable_register.as_ref()),
+            AsmInstr::assign(Dest::D, Comp::DMinusM),
+            AsmInstr::label(done),
+        ]
     }
 
     fn get_logical_command(
-        command: &str,
+        command: ArithOp,
         variable_register: &VariableRegister,
-    ) -> Result<Option<String>> {
-        let operator: Option<&str> = match command {
-            "and" => Some("// and\nD=D&M"),
-            "or" => Some("// or\nD=D|M"),
-            "not" => Some("// not\nD=!M"),
-            &_ => None,
+    ) -> Vec<AsmInstr> {
+        let comp = match command {
+            ArithOp::And => Comp::DAndM,
+            ArithOp::Or => Comp::DOrM,
+            _ => Comp::NotM,
         };
 
-        if operator.is_none() {
-            return Ok(None);
-        }
-
-        Ok(Some(unindent(
-            format!(
-                r#"
-        @{}
-        {}
-        "#,
-                variable_register.as_ref(),
-                operator.unwrap(),
-            )
-            .as_str(),
-        )))
+        vec![
+            AsmInstr::at(variable_register.as_ref()),
+            AsmInstr::comment(command.as_ref()),
+            AsmInstr::assign(Dest::D, comp),
+        ]
     }
 }
@@ -1,14 +1,38 @@
+pub mod asm;
+#[cfg(test)]
+mod emulator;
 pub mod helper;
+pub mod object;
+pub mod optimizer;
+pub mod options;
+pub mod source_map;
 
 use std::{fs::File, io::Write, path::Path};
 
-use anyhow::{Ok, Result};
-use helper::arithmetic::ArithmeticCommandHelper;
+use anyhow::{bail, Context, Ok, Result};
+use asm::{AsmInstr, Comp, Dest, Jump};
+use helper::arithmetic::{ArithmeticCommandHelper, COMPARISON_ROUTINES};
 use std::convert::AsRef;
 use strum_macros::AsRefStr;
-use unindent::{unindent, unindent_bytes};
 
-use parser::CommandType;
+use parser::{ArithOp, Command, CommandType, Segment};
+
+pub use object::Object;
+pub use optimizer::OptimizeReport;
+pub use options::{Bootstrap, CodeWriterOptions, OutputStyle, SegmentBases};
+pub use source_map::{SourceLocation, SourceMap};
+
+// shared_call_returnオプションで1つだけ出力する共通のcall、return処理のラベル。
+// 共通処理のラベルは`$`を2つ含め、`関数名$ラベル`の形になるvmのラベルと衝突しないようにする
+const CALL_ROUTINE: &str = "VM$$CALL";
+const RETURN_ROUTINE: &str = "VM$$RETURN";
+// ブートストラップからSys.initを呼ぶcallのリターンアドレス。プログラムに1つだけなので通し番号を付けない
+const BOOTSTRAP_RETURN_LABEL: &str = "VM$$BOOTSTRAP_RET";
+// specialize_push_popオプションで`A=A+1`を並べてアドレスを求めるインデックスの上限。
+// これより大きいと`@i D=A ... A=D+M`の一般形の方が短くなる
+const MAX_DIRECT_LOAD_INDEX: u16 = 2;
+const MAX_DIRECT_STORE_INDEX: u16 = 6;
+const MAX_TEMP_INDEX: u16 = 7;
 
 #[derive(AsRefStr, Clone, Copy)]
 pub enum VariableRegister {
@@ -17,65 +41,173 @@ pub enum VariableRegister {
     R15,
 }
 
-pub struct CodeWriter {
-    assembly_file: Box<dyn Write>,
+/// closeで書き出した内容についての情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReport {
+    /// optimizeオプションが有効な場合に最適化で減った命令数
+    pub optimization: Option<OptimizeReport>,
+    /// set_source_locationで指定したVMコマンドと、書き出したアセンブリの行の対応
+    pub source_map: SourceMap,
+}
+
+pub struct CodeWriter<W: Write = File> {
+    assembly_file: W,
+    // 生成した命令はcloseでまとめてテキストにするまでここに溜めておく
+    instructions: Vec<AsmInstr>,
+    // instructionsの各命令を生成したVMコマンドのsource_locationsの添字
+    instruction_sources: Vec<Option<usize>>,
+    source_locations: Vec<SourceLocation>,
+    current_source: Option<usize>,
+    options: CodeWriterOptions,
     vm_filename: String,
+    // ラベルをスコープするために直前のfunctionコマンドで定義された関数名を保持する
+    current_function: Option<String>,
+    // into_objectで書き出すオブジェクトの定義した関数と呼び出した関数
+    defined_functions: Vec<String>,
+    called_functions: Vec<String>,
     incremental_uniq_index: u16,
     // 無限ループで終了するようにENDラベルを必ず生成するのでVMコード内で記述されている場合に検知して重複を避ける
     has_end_label: bool,
+    // cache_top_of_stackオプションで、スタックの先頭の値をメモリに書かずにDに持っている状態
+    top_of_stack_in_d: bool,
+    // specialize_push_popオプションで直前に書いた`push constant 0|1`の、値を書き込む命令の位置と値
+    small_constant_push: Option<(usize, u16)>,
+}
+
+impl CodeWriter<File> {
+    /// 出力ファイルを作成して書き込む。ファイル名をset_filenameされるまでのstatic変数の接頭辞にする
+    pub fn create(output_file_path: &Path, options: CodeWriterOptions) -> Result<Self> {
+        let assembly_file = File::create(output_file_path)
+            .with_context(|| format!("failed to create {:?}", output_file_path))?;
+        Self::with_options(
+            assembly_file,
+            &Self::get_module_name(output_file_path)?,
+            options,
+        )
+    }
 }
 
-impl CodeWriter {
-    pub fn new(output_file_path: &Path) -> Self {
+impl<W: Write> CodeWriter<W> {
+    /// 任意の書き込み先(ファイル、標準出力、メモリ上のバッファなど)に書き込む。
+    /// module_nameはset_filenameされるまでのstatic変数の接頭辞に使う
+    pub fn new(assembly_file: W, module_name: &str) -> Result<Self> {
+        Self::with_options(assembly_file, module_name, CodeWriterOptions::default())
+    }
+
+    pub fn with_options(
+        assembly_file: W,
+        module_name: &str,
+        options: CodeWriterOptions,
+    ) -> Result<Self> {
         let mut code_writer = Self {
-            assembly_file: Box::new(File::create(output_file_path).unwrap()),
-            vm_filename: output_file_path
-                .file_stem()
-                .unwrap()
-                .to_string_lossy()
-                .to_string(),
+            assembly_file,
+            instructions: Vec::new(),
+            instruction_sources: Vec::new(),
+            source_locations: Vec::new(),
+            current_source: None,
+            options,
+            vm_filename: module_name.to_string(),
+            current_function: None,
+            defined_functions: Vec::new(),
+            called_functions: Vec::new(),
             incremental_uniq_index: 0,
             has_end_label: false,
+            top_of_stack_in_d: false,
+            small_constant_push: None,
         };
 
-        let _ = code_writer.write_bootstrap_code();
+        if code_writer.options.bootstrap != Bootstrap::Off {
+            code_writer.write_bootstrap_code()?;
+        }
 
-        code_writer
+        Ok(code_writer)
     }
 
     pub fn set_filename(&mut self, filename: &Path) -> Result<()> {
-        self.vm_filename = filename.file_stem().unwrap().to_string_lossy().to_string();
+        self.vm_filename = Self::get_module_name(filename)?;
+        self.current_function = None;
+        self.current_source = None;
+
+        Ok(())
+    }
+
+    /// 以降に書き込む命令を生成したVMコマンドの位置。closeで返すソースマップに使う。
+    /// output_styleがAnnotatedの場合は、VMコマンドの展開の見出しとしてコマンドと位置をコメントで書く
+    pub fn set_source_location(&mut self, location: SourceLocation) {
+        self.current_source = Some(self.source_locations.len());
+        if self.options.output_style == OutputStyle::Annotated {
+            self.write_code(vec![AsmInstr::comment(format!(
+                "{}:{}: {}",
+                location.vm_file, location.line, location.command
+            ))]);
+        }
+        self.source_locations.push(location);
+    }
 
+    /// 解釈済みのコマンドを対応するwrite_*メソッドに振り分けて書き込む
+    pub fn write_command(&mut self, command: &Command) -> Result<()> {
+        match command {
+            Command::Arithmetic(op) => self.write_arithmetic(*op),
+            Command::Push { segment, index } => {
+                self.write_push_pop(CommandType::Push, *segment, *index)
+            }
+            Command::Pop { segment, index } => {
+                self.write_push_pop(CommandType::Pop, *segment, *index)
+            }
+            Command::Label(label) => self.write_label(label),
+            Command::Goto(label) => self.write_goto(label),
+            Command::If(label) => self.write_if(label),
+            Command::Function { name, n_vars } => self.write_function(name, *n_vars),
+            Command::Call { name, n_args } => self.write_call(name, *n_args),
+            Command::Return => self.write_return(),
+        }
+    }
+
+    /// VMコードの行末コメントを生成するアセンブリにそのまま書き写す
+    pub fn write_comment(&mut self, comment: &str) -> Result<()> {
+        self.write_code(vec![AsmInstr::comment(comment)]);
         Ok(())
     }
 
-    pub fn write_arithmetic(&mut self, command: &str) -> Result<()> {
-        let is_single_operand = command == "neg" || command == "not";
+    pub fn write_arithmetic(&mut self, command: ArithOp) -> Result<()> {
+        if self.fold_minus_one(command) {
+            return Ok(());
+        }
+        let is_single_operand = matches!(command, ArithOp::Neg | ArithOp::Not);
         let variable_register = VariableRegister::R13;
 
-        self.write_code(unindent(
-            format!(
-                "{}
-{}
-{}
-{}
-{}",
-                self.get_pop_code(),
+        // 比較だけが分岐のラベルを使うので、それ以外では通し番号を進めない
+        let label_prefix = match command {
+            ArithOp::Eq | ArithOp::Gt | ArithOp::Lt => self.next_unique_label("cmp"),
+            _ => String::new(),
+        };
+
+        self.write_pop_top_of_stack();
+        self.write_code(
+            [
                 self.get_load_register_code(variable_register),
                 if !is_single_operand {
                     self.get_pop_code()
                 } else {
-                    "".to_string()
+                    Vec::new()
                 },
-                ArithmeticCommandHelper::get_command(
-                    command,
-                    &variable_register,
-                    self.incremental_uniq_index,
-                )?,
-                self.get_push_code(),
-            )
-            .as_str(),
-        ))?;
+                match command {
+                    ArithOp::Eq | ArithOp::Gt | ArithOp::Lt if self.options.shared_comparisons => {
+                        ArithmeticCommandHelper::get_shared_comparison_command(
+                            command,
+                            &label_prefix,
+                        )
+                    }
+                    _ => ArithmeticCommandHelper::get_command(
+                        command,
+                        &variable_register,
+                        &label_prefix,
+                    ),
+                },
+            ]
+            .concat(),
+        );
+        self.write_push_top_of_stack();
 
         Ok(())
     }
@@ -83,494 +215,936 @@ impl CodeWriter {
     pub fn write_push_pop(
         &mut self,
         command: CommandType,
-        segment: &str,
+        segment: Segment,
         index: u16,
     ) -> Result<()> {
-        self.write_code(self.get_segment_code(command, segment, index)?)?;
+        if !self.options.cache_top_of_stack {
+            self.write_code(self.get_segment_code(command, segment, index)?);
+        } else {
+            match command {
+                CommandType::Push => {
+                    let load_code = self.get_load_segment_code(segment, index)?;
+                    self.write_spill_top_of_stack();
+                    self.write_code(load_code);
+                    self.write_push_top_of_stack();
+                }
+                CommandType::Pop if self.top_of_stack_in_d => {
+                    self.write_code(self.get_store_top_of_stack_code(segment, index)?);
+                    self.top_of_stack_in_d = false;
+                }
+                _ => self.write_code(self.get_segment_code(command, segment, index)?),
+            }
+        }
+
+        // 専用の形では最後の命令が`M=0|1`(キャッシュする場合は`D=0|1`)になる
+        let is_small_constant_push =
+            command == CommandType::Push && segment == Segment::Constant && index <= 1;
+        if self.options.specialize_push_pop && is_small_constant_push {
+            self.small_constant_push = Some((self.instructions.len() - 1, index));
+        }
         Ok(())
     }
 
+    /// 直前の`push constant 1`と`neg`、`push constant 0`と`not`を、スタックへの-1の書き込みにまとめる。
+    /// 間にコメント以外の命令がある場合はまとめない
+    fn fold_minus_one(&mut self, command: ArithOp) -> bool {
+        let Some((position, value)) = self.small_constant_push else {
+            return false;
+        };
+        let is_minus_one = matches!((value, command), (1, ArithOp::Neg) | (0, ArithOp::Not));
+        let is_adjacent = self.instructions[position + 1..]
+            .iter()
+            .all(|instr| matches!(instr, AsmInstr::Comment(_)));
+        if !is_minus_one || !is_adjacent {
+            return false;
+        }
+        if let AsmInstr::C { comp, .. } = &mut self.instructions[position] {
+            *comp = Comp::MinusOne;
+        }
+        self.small_constant_push = None;
+        true
+    }
+
     pub fn write_label(&mut self, label: &str) -> Result<()> {
-        if !self.has_end_label {
-            if label == "END" {
-                self.has_end_label = true;
-            }
+        let label = self.get_scoped_label(label);
+        if !self.has_end_label && label == "END" {
+            self.has_end_label = true;
         }
-        self.write_code(format!(
-            "
-({})
-",
-            label
-        ))?;
+        self.write_spill_top_of_stack();
+        self.write_code(vec![AsmInstr::label(label)]);
         Ok(())
     }
 
     pub fn write_goto(&mut self, label: &str) -> Result<()> {
-        self.write_code(self.get_goto_code(label))?;
+        self.write_spill_top_of_stack();
+        self.write_code(self.get_goto_code(&self.get_scoped_label(label)));
         Ok(())
     }
 
     pub fn write_if(&mut self, label: &str) -> Result<()> {
-        self.write_code(self.get_if_code(label))?;
+        let label = self.get_scoped_label(label);
+        self.write_code(vec![AsmInstr::comment(format!("if-goto {}", label))]);
+        // 条件の値を取り出した後はスタック全体がメモリにあるので、ジャンプ先でもそのまま使える
+        self.write_pop_top_of_stack();
+        self.write_code(vec![
+            AsmInstr::at(label),
+            AsmInstr::jump(Comp::D, Jump::JNE),
+        ]);
         Ok(())
     }
 
     pub fn write_function(&mut self, function_name: &str, n_vars: u16) -> Result<()> {
-        self.write_code(self.get_function_code(function_name, n_vars))?;
+        self.current_function = Some(function_name.to_string());
+        self.defined_functions.push(function_name.to_string());
+        self.write_spill_top_of_stack();
+        let function_code = self.get_function_code(function_name, n_vars);
+        self.write_code(function_code);
         Ok(())
     }
 
     pub fn write_call(&mut self, function_name: &str, n_args: u16) -> Result<()> {
-        self.write_code(self.get_call_code(function_name, n_args))?;
+        if !self
+            .called_functions
+            .iter()
+            .any(|name| name == function_name)
+        {
+            self.called_functions.push(function_name.to_string());
+        }
+        let return_label = self.next_unique_label("ret");
+        self.write_spill_top_of_stack();
+        self.write_code(self.get_call_code(function_name, n_args, return_label));
         Ok(())
     }
 
     pub fn write_return(&mut self) -> Result<()> {
-        self.write_code(self.get_return_code())?;
+        self.write_spill_top_of_stack();
+        self.write_code(self.get_return_code());
         Ok(())
     }
 
-    pub fn close(mut self) -> Result<()> {
-        self.write_code(self.get_infinity_loop_code())?;
-        drop(self.assembly_file);
-        Ok(())
+    /// これまでに生成した命令列。closeで書き出す前に検査や書き換えをする処理から使う
+    pub fn instructions(&self) -> &[AsmInstr] {
+        &self.instructions
     }
 
-    pub fn increment_uniq_index(&mut self) {
-        self.incremental_uniq_index += 1;
+    /// 終了処理のコードを加えた命令列をテキストにして書き込み、書き込み先を返す
+    pub fn close(self) -> Result<W> {
+        Ok(self.close_with_report()?.0)
     }
 
-    fn write_bootstrap_code(&mut self) -> Result<()> {
-        self.write_code(self.get_bootstrap_code())?;
+    /// closeと同じだが、最適化で減った命令数とソースマップも返す
+    pub fn close_with_report(mut self) -> Result<(W, CloseReport)> {
+        self.write_spill_top_of_stack();
+        // 終了処理はどのVMコマンドにも対応しない
+        self.current_source = None;
+        self.write_code(self.get_infinity_loop_code());
+        // 共通処理は終了の無限ループより後ろに置き、call、returnからのジャンプでだけ実行する
+        self.write_code(self.get_shared_routines_code());
+        let optimization = self.options.optimize.then(|| {
+            optimizer::optimize_with_tags(&mut self.instructions, &mut self.instruction_sources)
+        });
+        // 最適化はコメントを手がかりにするので、コメントは最後に取り除く
+        if self.options.output_style == OutputStyle::Stripped {
+            let is_comment = self
+                .instructions
+                .iter()
+                .map(|instr| matches!(instr, AsmInstr::Comment(_)))
+                .collect::<Vec<_>>();
+            optimizer::remove_marked(
+                &mut self.instructions,
+                &mut self.instruction_sources,
+                &is_comment,
+            );
+        }
+        self.assembly_file
+            .write_all(asm::render(&self.instructions).as_bytes())?;
+        self.assembly_file.flush()?;
+        let source_map = SourceMap::new(
+            self.source_locations,
+            &self.instructions,
+            &self.instruction_sources,
+        );
+        Ok((
+            self.assembly_file,
+            CloseReport {
+                optimization,
+                source_map,
+            },
+        ))
+    }
+
+    /// 終了処理を加えずに、これまでに生成した命令列をリンク前のオブジェクトにする。
+    /// 書き込み先には何も書かない。最適化とコメントの除去はリンクしたプログラムのcloseでかける
+    pub fn into_object(mut self) -> Result<Object> {
+        if self.options.bootstrap != Bootstrap::Off {
+            bail!("bootstrap code cannot be written to an object");
+        }
+        self.write_spill_top_of_stack();
+        let imports = self
+            .called_functions
+            .into_iter()
+            .filter(|name| !self.defined_functions.contains(name))
+            .collect();
+        Ok(Object {
+            module: self.vm_filename,
+            exports: self.defined_functions,
+            imports,
+            instructions: self.instructions,
+        })
+    }
 
+    /// リンクするオブジェクトの命令列をそのまま書き込む。
+    /// オブジェクトはVMコマンドの位置を持たないので、ソースマップには含まれない
+    pub fn write_object(&mut self, object: &Object) -> Result<()> {
+        self.write_spill_top_of_stack();
+        self.current_source = None;
+        if object.instructions.contains(&AsmInstr::label("END")) {
+            self.has_end_label = true;
+        }
+        self.write_code(object.instructions.clone());
         Ok(())
     }
 
-    fn write_code(&mut self, code: String) -> Result<()> {
-        self.assembly_file.write(&unindent_bytes(code.as_bytes()))?;
+    fn get_module_name(path: &Path) -> Result<String> {
+        match path.file_stem() {
+            Some(file_stem) => Ok(file_stem.to_string_lossy().to_string()),
+            None => bail!("cannot determine module name from {:?}", path),
+        }
+    }
+
+    fn write_bootstrap_code(&mut self) -> Result<()> {
+        self.write_code(self.get_bootstrap_code());
+
         Ok(())
     }
 
-    fn get_bootstrap_code(&self) -> String {
-        format!(
-            "
-// bootstrap     
-@256
-D=A
-@SP
-M=D
+    fn write_code(&mut self, code: Vec<AsmInstr>) {
+        self.instruction_sources
+            .extend(std::iter::repeat_n(self.current_source, code.len()));
+        self.instructions.extend(code);
+    }
 
-@300
-D=A
-@LCL
-M=D
+    /// スタックの先頭をDに取り出す。Dにキャッシュしていればメモリを読まない
+    fn write_pop_top_of_stack(&mut self) {
+        if !std::mem::take(&mut self.top_of_stack_in_d) {
+            self.write_code(self.get_pop_code());
+        }
+    }
 
-@400
-D=A
-@ARG
-M=D
+    /// Dの値をスタックに積む。cache_top_of_stackオプションでは次のコマンドまでDに持っておく
+    fn write_push_top_of_stack(&mut self) {
+        if self.options.cache_top_of_stack {
+            self.top_of_stack_in_d = true;
+        } else {
+            self.write_code(self.get_push_code());
+        }
+    }
 
-@500
-D=A
-@THIS
-M=D
+    /// Dにキャッシュしているスタックの先頭をメモリに書き戻す。
+    /// ラベルやcall、returnの前ではスタック全体がメモリにある状態にそろえる
+    fn write_spill_top_of_stack(&mut self) {
+        if std::mem::take(&mut self.top_of_stack_in_d) {
+            self.write_code(self.get_push_code());
+        }
+    }
 
-@600
-D=A
-@THAT
-M=D
-{}
-",
-self.get_call_code("Sys.init", 0)
-        )
+    fn get_bootstrap_code(&self) -> Vec<AsmInstr> {
+        let set_pointer_code = |value: u16, symbol: &str| -> Vec<AsmInstr> {
+            vec![
+                AsmInstr::value(value),
+                AsmInstr::assign(Dest::D, Comp::A),
+                AsmInstr::at(symbol),
+                AsmInstr::assign(Dest::M, Comp::D),
+            ]
+        };
+        let segment_bases = &self.options.segment_bases;
+
+        [
+            vec![AsmInstr::comment("bootstrap")],
+            set_pointer_code(self.options.initial_sp, "SP"),
+            set_pointer_code(segment_bases.local, "LCL"),
+            set_pointer_code(segment_bases.argument, "ARG"),
+            set_pointer_code(segment_bases.this, "THIS"),
+            set_pointer_code(segment_bases.that, "THAT"),
+            self.get_call_code("Sys.init", 0, BOOTSTRAP_RETURN_LABEL.to_string()),
+        ]
+        .concat()
     }
 
-    fn get_segment_code(&self, command: CommandType, segment: &str, index: u16) -> Result<String> {
-        let index_for_temp_segment = index + 5; //TEMPセグメントはRAM[5~12]固定
+    fn get_segment_code(
+        &self,
+        command: CommandType,
+        segment: Segment,
+        index: u16,
+    ) -> Result<Vec<AsmInstr>> {
+        if self.options.specialize_push_pop {
+            if let Some(segment_code) =
+                self.get_specialized_segment_code(command, segment, index)?
+            {
+                return Ok(segment_code);
+            }
+        }
+
         let variable_register = VariableRegister::R13;
-        let segment_symbol_asm = match segment {
-            "local" => Some(format!("// local {}\n@LCL", index)),
-            "argument" => Some(format!("// argument {}\n@ARG", index)),
-            "this" => Some(format!("// this {}\n@THIS", index)),
-            "that" => Some(format!("// that {}\n@THAT", index)),
-            "temp" => Some(format!("// temp {}", index_for_temp_segment)),
-            "constant" => Some(format!("// constant {}\n@{}\n", index, index)),
-            "pointer" if index == 0 => Some(format!("// this {}\n@THIS", index)),
-            "pointer" if index == 1 => Some(format!("// that {}\n@THAT", index)),
-            "static" => Some(format!(
-                "// static {}\n@{}.{}\n",
-                index, self.vm_filename, index
-            )),
-            _ => None,
+        // popした値を書き込むアドレスはR13に退避しておく
+        let store_address_code = || -> Vec<AsmInstr> {
+            [
+                vec![
+                    AsmInstr::at(variable_register.as_ref()),
+                    AsmInstr::assign(Dest::M, Comp::D),
+                ],
+                self.get_pop_code(),
+                vec![
+                    AsmInstr::at(variable_register.as_ref()),
+                    AsmInstr::assign(Dest::A, Comp::M),
+                    AsmInstr::assign(Dest::M, Comp::D),
+                ],
+            ]
+            .concat()
         };
 
         let segment_code = match command {
-            CommandType::Push => match segment {
-                "constant" => {
-                    format!(
-                        "{}D=A\n{}",
-                        segment_symbol_asm.unwrap(),
-                        self.get_push_code()
-                    )
-                }
-                "temp" => {
-                    format!(
-                        "
-{}
-@{}
-D=M
-{}
-",
-                        segment_symbol_asm.unwrap(),
-                        index_for_temp_segment,
-                        self.get_push_code(),
-                    )
-                }
-                "pointer" | "static" => {
-                    format!(
-                        "
-{}
-D=M
-{}
-",
-                        segment_symbol_asm.unwrap(),
-                        self.get_push_code(),
-                    )
-                }
-                _ => {
-                    format!(
-                        "
-@{}
-D=A
-{}
-A=D+M
-D=M
-{}
-",
-                        index,
-                        segment_symbol_asm.unwrap(),
-                        self.get_push_code(),
-                    )
-                }
-            },
-            CommandType::Pop => match segment {
-                "static" => {
-                    format!(
-                        "
-{}
-{}
-M=D
-",
-                        self.get_pop_code(),
-                        segment_symbol_asm.unwrap()
-                    )
-                }
-                "temp" => {
-                    format!(
-                        "
-{}
-@{}
-D=A
-@{}
-M=D
-{}
-@{}
-A=M
-M=D
-",
-                        segment_symbol_asm.unwrap(),
-                        index_for_temp_segment,
-                        &variable_register.as_ref(),
-                        self.get_pop_code(),
-                        &variable_register.as_ref(),
-                    )
-                }
-                "pointer" => {
-                    format!(
-                        "
-{}
-D=A
-@{}
-M=D
-{}
-@{}
-A=M
-M=D
-",
-                        segment_symbol_asm.unwrap(),
-                        &variable_register.as_ref(),
-                        self.get_pop_code(),
-                        &variable_register.as_ref(),
-                    )
-                }
-                _ => {
-                    format!(
-                        "
-@{}
-D=A
-{}
-D=D+M
-@{}
-M=D
-{}
-@{}
-A=M
-M=D
-",
-                        index,
-                        segment_symbol_asm.unwrap(),
-                        &variable_register.as_ref(),
+            CommandType::Push => [
+                self.get_load_segment_code(segment, index)?,
+                self.get_push_code(),
+            ]
+            .concat(),
+            CommandType::Pop => {
+                let segment_symbol_asm = self.get_segment_symbol_code(segment, index)?;
+                match segment {
+                    Segment::Constant => bail!("cannot pop to the constant segment"),
+                    Segment::Static => [
                         self.get_pop_code(),
-                        &variable_register.as_ref(),
-                    )
+                        segment_symbol_asm,
+                        vec![AsmInstr::assign(Dest::M, Comp::D)],
+                    ]
+                    .concat(),
+                    Segment::Temp => [
+                        segment_symbol_asm,
+                        vec![
+                            AsmInstr::value(index + 5), //TEMPセグメントはRAM[5~12]固定
+                            AsmInstr::assign(Dest::D, Comp::A),
+                        ],
+                        store_address_code(),
+                    ]
+                    .concat(),
+                    Segment::Pointer => [
+                        segment_symbol_asm,
+                        vec![AsmInstr::assign(Dest::D, Comp::A)],
+                        store_address_code(),
+                    ]
+                    .concat(),
+                    _ => [
+                        vec![AsmInstr::value(index), AsmInstr::assign(Dest::D, Comp::A)],
+                        segment_symbol_asm,
+                        vec![AsmInstr::assign(Dest::D, Comp::DPlusM)],
+                        store_address_code(),
+                    ]
+                    .concat(),
                 }
-            },
-            _ => panic!("get segment code failed"),
+            }
+            _ => bail!("get segment code failed: {:?}", command),
         };
 
-        Ok(segment_code.to_string())
+        Ok(segment_code)
     }
 
-    fn get_goto_code(&self, label: &str) -> String {
-        format!(
-            "
-@{}
-0;JMP
-",
-            label
-        )
+    /// 一般形より短く書けるpush、popの形。該当しない場合はNone。
+    /// 小さいインデックスからのpushはget_load_segment_codeの側で短くする
+    fn get_specialized_segment_code(
+        &self,
+        command: CommandType,
+        segment: Segment,
+        index: u16,
+    ) -> Result<Option<Vec<AsmInstr>>> {
+        Ok(match (command, segment) {
+            // 0と1はDを経由せずスタックに直接書き込める
+            (CommandType::Push, Segment::Constant) if index <= 1 => Some(vec![
+                AsmInstr::comment(format!("constant {}", index)),
+                AsmInstr::at("SP"),
+                AsmInstr::assign(Dest::M, Comp::MPlusOne),
+                AsmInstr::assign(Dest::A, Comp::MMinusOne),
+                AsmInstr::assign(Dest::M, if index == 0 { Comp::Zero } else { Comp::One }),
+            ]),
+            (CommandType::Pop, _) => self
+                .get_direct_store_code(segment, index)?
+                .map(|store_code| [self.get_pop_code(), store_code].concat()),
+            _ => None,
+        })
     }
 
-    fn get_if_code(&self,label: &str) -> String{
-format!(
-            "
-// if-goto {}
-{}
-@{}
-D;JNE
-",
-            label,
-            self.get_pop_code(),
-            label,
-        )
+    /// アドレスの計算にDを使わずに、Dの値をセグメントへ書き込むコード。
+    /// local、argument、this、thatはspecialize_push_popオプションが有効な場合だけ扱う
+    fn get_direct_store_code(&self, segment: Segment, index: u16) -> Result<Option<Vec<AsmInstr>>> {
+        let segment_symbol_asm = self.get_segment_symbol_code(segment, index)?;
+        Ok(match segment {
+            Segment::Constant => bail!("cannot pop to the constant segment"),
+            Segment::Temp => Some(
+                [
+                    segment_symbol_asm,
+                    vec![
+                        AsmInstr::value(index + 5),
+                        AsmInstr::assign(Dest::M, Comp::D),
+                    ],
+                ]
+                .concat(),
+            ),
+            Segment::Pointer | Segment::Static => {
+                Some([segment_symbol_asm, vec![AsmInstr::assign(Dest::M, Comp::D)]].concat())
+            }
+            _ if !self.options.specialize_push_pop => None,
+            _ => self
+                .get_direct_address_code(segment, index, MAX_DIRECT_STORE_INDEX)?
+                .map(|address_code| {
+                    [address_code, vec![AsmInstr::assign(Dest::M, Comp::D)]].concat()
+                }),
+        })
     }
 
-    fn get_function_code(&self, function_name: &str, n_vars: u16) -> String {
-        let mut buffer = String::new();
-        buffer += &format!(
-            "
-({})
-",
-            function_name
+    /// local、argument、this、thatの要素のアドレスを、Dを使わずにAへ求める。
+    /// インデックスがmax_indexより大きい場合はNone
+    fn get_direct_address_code(
+        &self,
+        segment: Segment,
+        index: u16,
+        max_index: u16,
+    ) -> Result<Option<Vec<AsmInstr>>> {
+        let is_pointer_segment = matches!(
+            segment,
+            Segment::Local | Segment::Argument | Segment::This | Segment::That
         );
-        if n_vars == 0 {
-            return buffer;
+        if !is_pointer_segment || index > max_index {
+            return Ok(None);
         }
 
-        let init_var_asm = || -> String {
-            format!(
-                "
-@0
-D=A
-{}
-",
-                self.get_push_code()
-            )
-        };
+        let mut code = self.get_segment_symbol_code(segment, index)?;
+        code.push(match index {
+            0 => AsmInstr::assign(Dest::A, Comp::M),
+            _ => AsmInstr::assign(Dest::A, Comp::MPlusOne),
+        });
+        for _ in 1..index {
+            code.push(AsmInstr::assign(Dest::A, Comp::APlusOne));
+        }
+        Ok(Some(code))
+    }
 
-        for _ in 0..n_vars {
-            buffer += &init_var_asm();
+    /// セグメントの説明コメントと、ベースアドレス(temp以外)を指すA命令
+    fn get_segment_symbol_code(&self, segment: Segment, index: u16) -> Result<Vec<AsmInstr>> {
+        Ok(match segment {
+            Segment::Local => vec![
+                AsmInstr::comment(format!("local {}", index)),
+                AsmInstr::at("LCL"),
+            ],
+            Segment::Argument => vec![
+                AsmInstr::comment(format!("argument {}", index)),
+                AsmInstr::at("ARG"),
+            ],
+            Segment::This => vec![
+                AsmInstr::comment(format!("this {}", index)),
+                AsmInstr::at("THIS"),
+            ],
+            Segment::That => vec![
+                AsmInstr::comment(format!("that {}", index)),
+                AsmInstr::at("THAT"),
+            ],
+            // tempはRAM[5~12]の8ワードなので、範囲を確かめてからindex + 5を求める
+            Segment::Temp if index <= MAX_TEMP_INDEX => {
+                vec![AsmInstr::comment(format!("temp {}", index + 5))]
+            }
+            Segment::Temp => bail!(
+                "temp index must be between 0 and {}: {}",
+                MAX_TEMP_INDEX,
+                index
+            ),
+            Segment::Constant => vec![
+                AsmInstr::comment(format!("constant {}", index)),
+                AsmInstr::value(index),
+            ],
+            Segment::Pointer if index == 0 => vec![
+                AsmInstr::comment(format!("this {}", index)),
+                AsmInstr::at("THIS"),
+            ],
+            Segment::Pointer if index == 1 => vec![
+                AsmInstr::comment(format!("that {}", index)),
+                AsmInstr::at("THAT"),
+            ],
+            Segment::Pointer => bail!("pointer index must be 0 or 1: {}", index),
+            Segment::Static => vec![
+                AsmInstr::comment(format!("static {}", index)),
+                AsmInstr::at(format!("{}.{}", self.vm_filename, index)),
+            ],
+        })
+    }
+
+    /// セグメントの値をDに読み込む
+    fn get_load_segment_code(&self, segment: Segment, index: u16) -> Result<Vec<AsmInstr>> {
+        let segment_symbol_asm = self.get_segment_symbol_code(segment, index)?;
+        if self.options.specialize_push_pop {
+            if segment == Segment::Constant && index <= 1 {
+                return Ok(vec![
+                    segment_symbol_asm[0].clone(),
+                    AsmInstr::assign(Dest::D, if index == 0 { Comp::Zero } else { Comp::One }),
+                ]);
+            }
+            if let Some(address_code) =
+                self.get_direct_address_code(segment, index, MAX_DIRECT_LOAD_INDEX)?
+            {
+                return Ok([address_code, vec![AsmInstr::assign(Dest::D, Comp::M)]].concat());
+            }
         }
-        buffer
+        Ok(match segment {
+            Segment::Constant => {
+                [segment_symbol_asm, vec![AsmInstr::assign(Dest::D, Comp::A)]].concat()
+            }
+            Segment::Temp => [
+                segment_symbol_asm,
+                vec![
+                    AsmInstr::value(index + 5),
+                    AsmInstr::assign(Dest::D, Comp::M),
+                ],
+            ]
+            .concat(),
+            Segment::Pointer | Segment::Static => {
+                [segment_symbol_asm, vec![AsmInstr::assign(Dest::D, Comp::M)]].concat()
+            }
+            _ => [
+                vec![AsmInstr::value(index), AsmInstr::assign(Dest::D, Comp::A)],
+                segment_symbol_asm,
+                vec![
+                    AsmInstr::assign(Dest::A, Comp::DPlusM),
+                    AsmInstr::assign(Dest::D, Comp::M),
+                ],
+            ]
+            .concat(),
+        })
     }
 
-    fn get_call_code(&self, function_name: &str, n_args: u16) -> String {
-        let return_address_symbol =
-            format!("{}$ret.{}", function_name, self.incremental_uniq_index);
-        let gen_push_segment_code = |symbol: &str| -> String {
-            format!(
-                "
-@{}
-D=M
-{}
-",
-                symbol,
-                self.get_push_code()
-            )
+    /// Dにキャッシュしているスタックの先頭をセグメントに書き込む
+    fn get_store_top_of_stack_code(&self, segment: Segment, index: u16) -> Result<Vec<AsmInstr>> {
+        if let Some(store_code) = self.get_direct_store_code(segment, index)? {
+            return Ok(store_code);
+        }
+        let value_register = VariableRegister::R14;
+        let address_register = VariableRegister::R13;
+        // アドレスの計算でDを使うので値をR14に退避しておく
+        Ok([
+            vec![
+                AsmInstr::at(value_register.as_ref()),
+                AsmInstr::assign(Dest::M, Comp::D),
+                AsmInstr::value(index),
+                AsmInstr::assign(Dest::D, Comp::A),
+            ],
+            self.get_segment_symbol_code(segment, index)?,
+            vec![
+                AsmInstr::assign(Dest::D, Comp::DPlusM),
+                AsmInstr::at(address_register.as_ref()),
+                AsmInstr::assign(Dest::M, Comp::D),
+                AsmInstr::at(value_register.as_ref()),
+                AsmInstr::assign(Dest::D, Comp::M),
+                AsmInstr::at(address_register.as_ref()),
+                AsmInstr::assign(Dest::A, Comp::M),
+                AsmInstr::assign(Dest::M, Comp::D),
+            ],
+        ]
+        .concat())
+    }
+
+    /// 比較やcallのために生成するラベル。別々に翻訳したアセンブリをつなげても重複しないよう、
+    /// `Main$cmp$3`のようにvmファイル名と通し番号を含める。呼び出すたびに通し番号を進める。
+    /// vmのラベルは`$`を含められず`関数名$ラベル`の`$`は1つなので、`$`を2つ使えば衝突しない。
+    /// 共通処理のラベルは`VM$$CALL`のように`$`を続けるので、こちらとも重ならない
+    fn next_unique_label(&mut self, kind: &str) -> String {
+        let label = format!(
+            "{}${}${}",
+            self.vm_filename, kind, self.incremental_uniq_index
+        );
+        self.incremental_uniq_index += 1;
+        label
+    }
+
+    /// VMコードのラベルを関数名(関数の外ではファイル名)でスコープした`functionName$label`の形にする
+    fn get_scoped_label(&self, label: &str) -> String {
+        match &self.current_function {
+            Some(function_name) => format!("{}${}", function_name, label),
+            None if self.options.raw_labels_outside_function => label.to_string(),
+            None => format!("{}${}", self.vm_filename, label),
+        }
+    }
+
+    fn get_goto_code(&self, label: &str) -> Vec<AsmInstr> {
+        vec![AsmInstr::at(label), AsmInstr::jump(Comp::Zero, Jump::JMP)]
+    }
+
+    fn get_function_code(&mut self, function_name: &str, n_vars: u16) -> Vec<AsmInstr> {
+        let mut buffer = vec![AsmInstr::label(function_name)];
+
+        let init_var_asm = || -> Vec<AsmInstr> {
+            [
+                vec![AsmInstr::value(0), AsmInstr::assign(Dest::D, Comp::A)],
+                self.get_push_code(),
+            ]
+            .concat()
         };
-        format!(
-            "
-// call function {}
-// push returnAddress
-@{}
-D=A
-{}
 
-{}
-{}
-{}
-{}
+        let unrolled_code = (0..n_vars).flat_map(|_| init_var_asm()).collect::<Vec<_>>();
+        match self.options.compact_locals_threshold {
+            Some(threshold) if n_vars > threshold => {
+                // 展開した形も候補に含め、命令数が最も少ない形を選ぶ。同じ数なら先の候補を使う
+                let candidates = [
+                    unrolled_code,
+                    self.get_zero_block_code(n_vars),
+                    self.get_init_locals_loop_code(function_name, n_vars),
+                ];
+                let (chosen, smallest) = candidates
+                    .into_iter()
+                    .enumerate()
+                    .min_by_key(|(_, code)| {
+                        code.iter().filter(|instr| instr.is_instruction()).count()
+                    })
+                    .unwrap();
+                // ループのラベルを使った場合だけ通し番号を進める
+                if chosen == 2 {
+                    self.incremental_uniq_index += 1;
+                }
+                buffer.extend(smallest);
+            }
+            _ => buffer.extend(unrolled_code),
+        }
+        buffer
+    }
 
-// ARG=SP-5-nArgs
-@5
-D=A
-@{}
-D=D+A
-@SP
-D=M-D
-@ARG
-M=D
+    /// スタックの上にM=0を並べて書き込み、最後にSPをまとめて進める
+    fn get_zero_block_code(&self, n_vars: u16) -> Vec<AsmInstr> {
+        let mut code = vec![
+            AsmInstr::comment(format!("init {} locals", n_vars)),
+            AsmInstr::at("SP"),
+            AsmInstr::assign(Dest::A, Comp::M),
+            AsmInstr::assign(Dest::M, Comp::Zero),
+        ];
+        for _ in 1..n_vars {
+            code.push(AsmInstr::assign(Dest::A, Comp::APlusOne));
+            code.push(AsmInstr::assign(Dest::M, Comp::Zero));
+        }
+        code.extend([
+            AsmInstr::assign(Dest::D, Comp::APlusOne),
+            AsmInstr::at("SP"),
+            AsmInstr::assign(Dest::M, Comp::D),
+        ]);
+        code
+    }
 
-// LCL=SP
-@SP
-D=M
-@LCL
-M=D
+    /// Dを残りの個数として0をpushするループ。n_varsによらず命令数は一定。
+    /// ラベルは`$`を2つ含めて、VMコードのラベルをスコープした`functionName$label`と重ならないようにする
+    fn get_init_locals_loop_code(&self, function_name: &str, n_vars: u16) -> Vec<AsmInstr> {
+        let loop_label = format!(
+            "{}$init_locals${}",
+            function_name, self.incremental_uniq_index
+        );
+        vec![
+            AsmInstr::comment(format!("init {} locals", n_vars)),
+            AsmInstr::value(n_vars),
+            AsmInstr::assign(Dest::D, Comp::A),
+            AsmInstr::label(&loop_label),
+            AsmInstr::at("SP"),
+            AsmInstr::assign(Dest::AM, Comp::MPlusOne),
+            AsmInstr::assign(Dest::A, Comp::AMinusOne),
+            AsmInstr::assign(Dest::M, Comp::Zero),
+            AsmInstr::assign(Dest::D, Comp::DMinusOne),
+            AsmInstr::at(loop_label),
+            AsmInstr::jump(Comp::D, Jump::JGT),
+        ]
+    }
 
-// goto function {}
-{}
+    fn get_call_code(
+        &self,
+        function_name: &str,
+        n_args: u16,
+        return_address_symbol: String,
+    ) -> Vec<AsmInstr> {
+        if self.options.shared_call_return {
+            return self.get_shared_call_code(function_name, n_args, return_address_symbol);
+        }
 
-({})
-",
-            function_name,
-            return_address_symbol,
+        [
+            vec![
+                AsmInstr::comment(format!("call function {}", function_name)),
+                AsmInstr::comment("push returnAddress"),
+                AsmInstr::at(&return_address_symbol),
+                AsmInstr::assign(Dest::D, Comp::A),
+            ],
             self.get_push_code(),
-            gen_push_segment_code("LCL"),
-            gen_push_segment_code("ARG"),
-            gen_push_segment_code("THIS"),
-            gen_push_segment_code("THAT"),
-            n_args,
-            function_name,
+            self.get_save_frame_code(),
+            vec![
+                AsmInstr::comment("ARG=SP-5-nArgs"),
+                AsmInstr::value(5),
+                AsmInstr::assign(Dest::D, Comp::A),
+                AsmInstr::value(n_args),
+                AsmInstr::assign(Dest::D, Comp::DPlusA),
+                AsmInstr::at("SP"),
+                AsmInstr::assign(Dest::D, Comp::MMinusD),
+                AsmInstr::at("ARG"),
+                AsmInstr::assign(Dest::M, Comp::D),
+                AsmInstr::comment("LCL=SP"),
+                AsmInstr::at("SP"),
+                AsmInstr::assign(Dest::D, Comp::M),
+                AsmInstr::at("LCL"),
+                AsmInstr::assign(Dest::M, Comp::D),
+                AsmInstr::comment(format!("goto function {}", function_name)),
+            ],
             self.get_goto_code(function_name),
-            return_address_symbol,
-        )
+            vec![AsmInstr::label(return_address_symbol)],
+        ]
+        .concat()
     }
 
-    fn get_return_code(&self) -> String {
-        format!(
-            "
-// return
-@LCL
-D=M
-@frame
-M=D
+    /// 呼び出し元のLCL、ARG、THIS、THATをスタックに保存する
+    fn get_save_frame_code(&self) -> Vec<AsmInstr> {
+        ["LCL", "ARG", "THIS", "THAT"]
+            .into_iter()
+            .flat_map(|symbol| {
+                [
+                    vec![AsmInstr::at(symbol), AsmInstr::assign(Dest::D, Comp::M)],
+                    self.get_push_code(),
+                ]
+                .concat()
+            })
+            .collect()
+    }
 
-@5
-D=A
-@frame
-A=M-D
-D=M
-@retAddr
-M=D
+    /// 共通のcall処理に関数のアドレスをR13、nArgsをR14、リターンアドレスをR15で渡して飛ぶ
+    fn get_shared_call_code(
+        &self,
+        function_name: &str,
+        n_args: u16,
+        return_address_symbol: String,
+    ) -> Vec<AsmInstr> {
+        let pass_code = |address: AsmInstr, register: VariableRegister| -> Vec<AsmInstr> {
+            vec![
+                address,
+                AsmInstr::assign(Dest::D, Comp::A),
+                AsmInstr::at(register.as_ref()),
+                AsmInstr::assign(Dest::M, Comp::D),
+            ]
+        };
 
-// pop to ARG
-{}
-@ARG
-A=M
-M=D
-D=A
-@SP
-M=D+1
+        [
+            vec![AsmInstr::comment(format!(
+                "call function {}",
+                function_name
+            ))],
+            pass_code(AsmInstr::at(function_name), VariableRegister::R13),
+            pass_code(AsmInstr::value(n_args), VariableRegister::R14),
+            pass_code(AsmInstr::at(&return_address_symbol), VariableRegister::R15),
+            self.get_goto_code(CALL_ROUTINE),
+            vec![AsmInstr::label(return_address_symbol)],
+        ]
+        .concat()
+    }
 
-@frame
-A=M-1
-D=M
-@THAT
-M=D
+    fn get_call_routine_code(&self) -> Vec<AsmInstr> {
+        [
+            vec![
+                AsmInstr::label(CALL_ROUTINE),
+                AsmInstr::comment("push returnAddress"),
+                AsmInstr::at(VariableRegister::R15.as_ref()),
+                AsmInstr::assign(Dest::D, Comp::M),
+            ],
+            self.get_push_code(),
+            self.get_save_frame_code(),
+            vec![
+                AsmInstr::comment("ARG=SP-5-nArgs"),
+                AsmInstr::value(5),
+                AsmInstr::assign(Dest::D, Comp::A),
+                AsmInstr::at(VariableRegister::R14.as_ref()),
+                AsmInstr::assign(Dest::D, Comp::DPlusM),
+                AsmInstr::at("SP"),
+                AsmInstr::assign(Dest::D, Comp::MMinusD),
+                AsmInstr::at("ARG"),
+                AsmInstr::assign(Dest::M, Comp::D),
+                AsmInstr::comment("LCL=SP"),
+                AsmInstr::at("SP"),
+                AsmInstr::assign(Dest::D, Comp::M),
+                AsmInstr::at("LCL"),
+                AsmInstr::assign(Dest::M, Comp::D),
+                AsmInstr::comment("goto function"),
+                AsmInstr::at(VariableRegister::R13.as_ref()),
+                AsmInstr::assign(Dest::A, Comp::M),
+                AsmInstr::jump(Comp::Zero, Jump::JMP),
+            ],
+        ]
+        .concat()
+    }
 
-@2
-D=A
-@frame
-A=M-D
-D=M
-@THIS
-M=D
+    /// 共通処理のうち実際にジャンプしてくるものだけを返す。
+    /// 書き込んだオブジェクトが共通処理を使う場合もあるので、オプションではなく命令列から判断する
+    fn get_shared_routines_code(&self) -> Vec<AsmInstr> {
+        let is_used = |routine: &str| self.instructions.contains(&AsmInstr::at(routine));
+        let mut code = Vec::new();
+        if is_used(CALL_ROUTINE) {
+            code.extend(self.get_call_routine_code());
+        }
+        if is_used(RETURN_ROUTINE) {
+            code.push(AsmInstr::label(RETURN_ROUTINE));
+            code.extend(self.get_return_sequence_code());
+        }
+        if COMPARISON_ROUTINES.into_iter().any(is_used) {
+            code.extend(ArithmeticCommandHelper::get_comparison_routine(
+                &VariableRegister::R13,
+            ));
+        }
+        code
+    }
 
-@3
-D=A
-@frame
-A=M-D
-D=M
-@ARG
-M=D
+    fn get_return_code(&self) -> Vec<AsmInstr> {
+        if self.options.shared_call_return {
+            return [
+                vec![AsmInstr::comment("return")],
+                self.get_goto_code(RETURN_ROUTINE),
+            ]
+            .concat();
+        }
+        self.get_return_sequence_code()
+    }
 
-@4
-D=A
-@frame
-A=M-D
-D=M
-@LCL
-M=D
+    fn get_return_sequence_code(&self) -> Vec<AsmInstr> {
+        // frameとretAddrはシンボルにするとstatic変数と同じRAM[16]以降に割り当てられるので予約済みのレジスタに置く
+        let frame = VariableRegister::R14;
+        let return_address = VariableRegister::R15;
+        // *(frame - offset)をsymbolに書き戻す
+        let restore_code = |offset: u16, symbol: &str| -> Vec<AsmInstr> {
+            vec![
+                AsmInstr::value(offset),
+                AsmInstr::assign(Dest::D, Comp::A),
+                AsmInstr::at(frame.as_ref()),
+                AsmInstr::assign(Dest::A, Comp::MMinusD),
+                AsmInstr::assign(Dest::D, Comp::M),
+                AsmInstr::at(symbol),
+                AsmInstr::assign(Dest::M, Comp::D),
+            ]
+        };
 
-//goto retAddr
-@retAddr
-A=M
-0;JMP
-",
+        [
+            vec![
+                AsmInstr::comment("return"),
+                AsmInstr::at("LCL"),
+                AsmInstr::assign(Dest::D, Comp::M),
+                AsmInstr::at(frame.as_ref()),
+                AsmInstr::assign(Dest::M, Comp::D),
+            ],
+            restore_code(5, return_address.as_ref()),
+            vec![AsmInstr::comment("pop to ARG")],
             self.get_pop_code(),
-        )
+            vec![
+                AsmInstr::at("ARG"),
+                AsmInstr::assign(Dest::A, Comp::M),
+                AsmInstr::assign(Dest::M, Comp::D),
+                AsmInstr::assign(Dest::D, Comp::A),
+                AsmInstr::at("SP"),
+                AsmInstr::assign(Dest::M, Comp::DPlusOne),
+                AsmInstr::at(frame.as_ref()),
+                AsmInstr::assign(Dest::A, Comp::MMinusOne),
+                AsmInstr::assign(Dest::D, Comp::M),
+                AsmInstr::at("THAT"),
+                AsmInstr::assign(Dest::M, Comp::D),
+            ],
+            restore_code(2, "THIS"),
+            restore_code(3, "ARG"),
+            restore_code(4, "LCL"),
+            vec![
+                AsmInstr::comment("goto retAddr"),
+                AsmInstr::at(return_address.as_ref()),
+                AsmInstr::assign(Dest::A, Comp::M),
+                AsmInstr::jump(Comp::Zero, Jump::JMP),
+            ],
+        ]
+        .concat()
     }
 
-    fn get_push_code(&self) -> String {
-        "
-// push
-@SP
-A=M
-M=D
-@SP
-M=M+1
-"
-        .to_string()
+    fn get_push_code(&self) -> Vec<AsmInstr> {
+        vec![
+            AsmInstr::comment("push"),
+            AsmInstr::at("SP"),
+            AsmInstr::assign(Dest::A, Comp::M),
+            AsmInstr::assign(Dest::M, Comp::D),
+            AsmInstr::at("SP"),
+            AsmInstr::assign(Dest::M, Comp::MPlusOne),
+        ]
     }
 
-    fn get_pop_code(&self) -> String {
-        "
-// pop
-@SP
-M=M-1
-A=M
-D=M
-"
-        .to_string()
+    fn get_pop_code(&self) -> Vec<AsmInstr> {
+        vec![
+            AsmInstr::comment("pop"),
+            AsmInstr::at("SP"),
+            AsmInstr::assign(Dest::M, Comp::MMinusOne),
+            AsmInstr::assign(Dest::A, Comp::M),
+            AsmInstr::assign(Dest::D, Comp::M),
+        ]
     }
 
-    fn get_load_register_code(&self, register: VariableRegister) -> String {
-        format!(
-            "@{}
-M=D",
-            register.as_ref()
-        )
+    fn get_load_register_code(&self, register: VariableRegister) -> Vec<AsmInstr> {
+        vec![
+            AsmInstr::at(register.as_ref()),
+            AsmInstr::assign(Dest::M, Comp::D),
+        ]
     }
 
-    fn get_infinity_loop_code(&self) -> String {
-        format!(
-            "{}
-@END
-0;JMP
-",
-            if self.has_end_label { "" } else { "(END)" }
-        )
-        .to_string()
+    fn get_infinity_loop_code(&self) -> Vec<AsmInstr> {
+        let mut code = Vec::new();
+        if !self.has_end_label {
+            code.push(AsmInstr::label("END"));
+        }
+        code.extend([AsmInstr::at("END"), AsmInstr::jump(Comp::Zero, Jump::JMP)]);
+        code
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use parser::Parser;
     use pretty_assertions::assert_eq;
-    use rand::distr::{Alphanumeric, SampleString};
-    use std::{fs, io::Read};
+    use std::io::Cursor;
 
     use super::*;
+    use crate::emulator::Emulator;
 
-    fn get_code_writer() -> Result<(CodeWriter, String)> {
-        fs::create_dir_all("../target/test/data")?;
-        let mut test_file_name = Alphanumeric.sample_string(&mut rand::rng(), 5);
-        test_file_name = format!("{}.vm", test_file_name);
-        let file_path = Path::new("../target/test/data").join(&test_file_name);
-        Ok((
-            CodeWriter::new(&file_path),
-            file_path.to_string_lossy().to_string(),
-        ))
+    fn get_code_writer() -> Result<CodeWriter<Vec<u8>>> {
+        CodeWriter::new(Vec::new(), "Test")
+    }
+
+    fn output(code_writer: &CodeWriter<Vec<u8>>) -> String {
+        asm::render(&code_writer.instructions)
+    }
+
+    /// VMコードを翻訳したアセンブリをエミュレータで実行する
+    fn run_vm_code(vm_code: &str) -> Result<Emulator> {
+        run_vm_code_with_options(vm_code, CodeWriterOptions::default())
+    }
+
+    /// VMコードを解析してvm_filenameのファイルとして書き込んだCodeWriterを返す
+    fn write_vm_code(
+        vm_filename: &str,
+        vm_code: &str,
+        options: CodeWriterOptions,
+    ) -> Result<CodeWriter<Vec<u8>>> {
+        let mut code_writer = CodeWriter::with_options(Vec::new(), "Test", options)?;
+        code_writer.set_filename(Path::new(vm_filename))?;
+        let mut parser = Parser::new(Cursor::new(vm_code.to_string()));
+        while parser.has_more_lines()? {
+            parser.advance()?;
+            if let Some(command) = parser.command() {
+                code_writer.write_command(command)?;
+            }
+        }
+        Ok(code_writer)
+    }
+
+    fn run_vm_code_with_options(vm_code: &str, options: CodeWriterOptions) -> Result<Emulator> {
+        let code_writer = write_vm_code("Main.vm", vm_code, options)?;
+        let asm = String::from_utf8(code_writer.close()?)?;
+
+        let mut emulator = Emulator::new(&asm)?;
+        assert!(emulator.run(100_000));
+        Ok(emulator)
     }
 
     fn normalize(s: &str) -> String {
@@ -583,11 +1157,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_io_errors_are_reported() -> Result<()> {
+        let missing_dir = Path::new("../target/no-such-dir/Out.asm");
+        assert!(CodeWriter::create(missing_dir, CodeWriterOptions::default()).is_err());
+
+        let mut code_writer = get_code_writer()?;
+        assert!(code_writer.set_filename(Path::new("..")).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_write_segment_when_constant() -> Result<()> {
-        let (code_writer, test_file_name) = get_code_writer()?;
-        let (segment, index) = ("constant", 10);
-        let asm_file_content = code_writer.get_segment_code(CommandType::Push, &segment, index)?;
+        let code_writer = get_code_writer()?;
+        let (segment, index) = (Segment::Constant, 10);
+        let asm_file_content =
+            asm::render(&code_writer.get_segment_code(CommandType::Push, segment, index)?);
 
         let expect_asm = "// constant 10
         @10
@@ -599,16 +1184,51 @@ mod tests {
         @SP
         M=M+1";
         assert_eq!(normalize(expect_asm), normalize(&asm_file_content));
+        Ok(())
+    }
 
-        fs::remove_file(test_file_name)?;
+    #[test]
+    fn test_write_segment_when_invalid() -> Result<()> {
+        let code_writer = get_code_writer()?;
+        assert!(code_writer
+            .get_segment_code(CommandType::Pop, Segment::Constant, 3)
+            .is_err());
+        assert!(code_writer
+            .get_segment_code(CommandType::Push, Segment::Pointer, 2)
+            .is_err());
+
+        // CodeWriterを直接使う場合もtempの範囲外はR13以降やオーバーフローにせずエラーにする
+        let mut code_writer = get_code_writer()?;
+        for (command, index) in [
+            (CommandType::Push, 8),
+            (CommandType::Pop, 9),
+            (CommandType::Push, u16::MAX),
+        ] {
+            assert!(code_writer
+                .write_push_pop(command, Segment::Temp, index)
+                .is_err());
+        }
+        let mut code_writer = CodeWriter::with_options(
+            Vec::new(),
+            "Test",
+            CodeWriterOptions {
+                specialize_push_pop: true,
+                cache_top_of_stack: true,
+                ..Default::default()
+            },
+        )?;
+        assert!(code_writer
+            .write_push_pop(CommandType::Pop, Segment::Temp, u16::MAX)
+            .is_err());
         Ok(())
     }
 
     #[test]
     fn test_write_segment_when_push() -> Result<()> {
-        let (code_writer, test_file_name) = get_code_writer()?;
-        let (segment, index) = ("that", 5);
-        let asm_file_content = code_writer.get_segment_code(CommandType::Push, &segment, index)?;
+        let code_writer = get_code_writer()?;
+        let (segment, index) = (Segment::That, 5);
+        let asm_file_content =
+            asm::render(&code_writer.get_segment_code(CommandType::Push, segment, index)?);
 
         let expect_asm = format!(
             "@{}
@@ -626,19 +1246,16 @@ mod tests {
             index, index,
         );
         assert_eq!(normalize(&expect_asm), normalize(&asm_file_content));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_push_command() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
-        let (segment, index) = ("that", 5);
-        code_writer.write_push_pop(CommandType::Push, &segment, index)?;
+        let mut code_writer = get_code_writer()?;
+        let (segment, index) = (Segment::That, 5);
+        code_writer.write_push_pop(CommandType::Push, segment, index)?;
 
-        let mut asm_file_content = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut asm_file_content)?;
+        let asm_file_content = output(&code_writer);
 
         let expect_asm = format!(
             "{}
@@ -654,23 +1271,21 @@ mod tests {
         M=D
         @SP
         M=M+1",
-            code_writer.get_bootstrap_code(),
-            index, index,
+            asm::render(&code_writer.get_bootstrap_code()),
+            index,
+            index,
         );
         assert_eq!(normalize(&expect_asm), normalize(&asm_file_content));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_push_command_when_temp() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
-        let (segment, index) = ("temp", 6);
-        code_writer.write_push_pop(CommandType::Push, &segment, index)?;
+        let mut code_writer = get_code_writer()?;
+        let (segment, index) = (Segment::Temp, 6);
+        code_writer.write_push_pop(CommandType::Push, segment, index)?;
 
-        let mut asm_file_content = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut asm_file_content)?;
+        let asm_file_content = output(&code_writer);
 
         let expect_asm = format!(
             "
@@ -684,24 +1299,21 @@ mod tests {
         M=D
         @SP
         M=M+1",
-            code_writer.get_bootstrap_code(),
+            asm::render(&code_writer.get_bootstrap_code()),
             index + 5,
             index + 5,
         );
         assert_eq!(normalize(&expect_asm), normalize(&asm_file_content));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_pop_command_when_static() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
-        let (segment, index) = ("static", 10);
-        code_writer.write_push_pop(CommandType::Pop, &segment, index)?;
+        let mut code_writer = get_code_writer()?;
+        let (segment, index) = (Segment::Static, 10);
+        code_writer.write_push_pop(CommandType::Pop, segment, index)?;
 
-        let mut asm_file_content = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut asm_file_content)?;
+        let asm_file_content = output(&code_writer);
 
         let expect_asm = format!(
             "{}
@@ -713,28 +1325,22 @@ mod tests {
         // static {}
         @{}.{}
         M=D",
-            code_writer.get_bootstrap_code(),
+            asm::render(&code_writer.get_bootstrap_code()),
             index,
-            Path::new(&test_file_name)
-                .file_stem()
-                .unwrap()
-                .to_string_lossy(),
+            "Test",
             index,
         );
         assert_eq!(normalize(&expect_asm), normalize(&asm_file_content));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_pop_command_when_temp() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
-        let (segment, index) = ("temp", 6);
-        code_writer.write_push_pop(CommandType::Pop, &segment, index)?;
+        let mut code_writer = get_code_writer()?;
+        let (segment, index) = (Segment::Temp, 6);
+        code_writer.write_push_pop(CommandType::Pop, segment, index)?;
 
-        let mut asm_file_content = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut asm_file_content)?;
+        let asm_file_content = output(&code_writer);
 
         let expect_asm = format!(
             "{}
@@ -755,25 +1361,22 @@ D=M
 A=M
 M=D
         ",
-            code_writer.get_bootstrap_code(),
+            asm::render(&code_writer.get_bootstrap_code()),
             index + 5,
             index + 5,
             VariableRegister::R13.as_ref(),
         );
         assert_eq!(normalize(&expect_asm), normalize(&asm_file_content));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_pop_command() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
-        let (segment, index) = ("local", 6);
-        code_writer.write_push_pop(CommandType::Pop, &segment, index)?;
+        let mut code_writer = get_code_writer()?;
+        let (segment, index) = (Segment::Local, 6);
+        code_writer.write_push_pop(CommandType::Pop, segment, index)?;
 
-        let mut asm_file_content = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut asm_file_content)?;
+        let asm_file_content = output(&code_writer);
 
         let expect_asm = format!(
             "{}
@@ -793,24 +1396,23 @@ M=D
         A=M
         M=D
         ",
-            code_writer.get_bootstrap_code(),
-            index, index,
+            asm::render(&code_writer.get_bootstrap_code()),
+            index,
+            index,
         );
         assert_eq!(normalize(&expect_asm), normalize(&asm_file_content));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_write_arithmetic() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
-        code_writer.write_arithmetic("add")?;
+        let mut code_writer = get_code_writer()?;
+        code_writer.write_arithmetic(ArithOp::Add)?;
 
-        let mut asm_file_content = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut asm_file_content)?;
+        let asm_file_content = output(&code_writer);
 
-        let expect_asm = format!("{}
+        let expect_asm = format!(
+            "{}
         // pop
         @SP
         M=M-1
@@ -832,23 +1434,21 @@ M=D
         M=D
         @SP
         M=M+1",
-        code_writer.get_bootstrap_code(),
-    );
+            asm::render(&code_writer.get_bootstrap_code()),
+        );
         assert_eq!(normalize(&expect_asm), normalize(&asm_file_content));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_write_arithmetic_when_sub() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
-        code_writer.write_arithmetic("sub")?;
+        let mut code_writer = get_code_writer()?;
+        code_writer.write_arithmetic(ArithOp::Sub)?;
 
-        let mut asm_file_content = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut asm_file_content)?;
+        let asm_file_content = output(&code_writer);
 
-        let expect_asm = format!("{}
+        let expect_asm = format!(
+            "{}
         // pop
         @SP
         M=M-1
@@ -870,24 +1470,22 @@ M=D
         M=D
         @SP
         M=M+1",
-        code_writer.get_bootstrap_code()
-    );
+            asm::render(&code_writer.get_bootstrap_code())
+        );
         assert_eq!(normalize(&expect_asm), normalize(&asm_file_content));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_write_call_code() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
+        let mut code_writer = get_code_writer()?;
         let (function_name, n_args) = ("SimpleFunction.test", 2);
         code_writer.write_call(function_name, n_args)?;
         let expect = format!(
             "{}
 // call function SimpleFunction.test
 // push returnAddress
-@{}$ret.0
+@Test$ret$0
 D=A
 // push
 @SP
@@ -952,23 +1550,22 @@ M=D
 @{}
 0;JMP
 
-({}$ret.0)
+(Test$ret$0)
 ",
-            code_writer.get_bootstrap_code(),
-            function_name, n_args, function_name, function_name, function_name,
+            asm::render(&code_writer.get_bootstrap_code()),
+            n_args,
+            function_name,
+            function_name,
         );
-        let mut actual = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut actual)?;
+        let actual = output(&code_writer);
 
         assert_eq!(normalize(&expect), normalize(&actual));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_write_function_code() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
+        let mut code_writer = get_code_writer()?;
         let (function_name, n_args) = ("SimpleFunction.test", 1);
         code_writer.write_function(function_name, n_args)?;
 
@@ -985,21 +1582,18 @@ M=D
 @SP
 M=M+1
 ",
-            code_writer.get_bootstrap_code(),
+            asm::render(&code_writer.get_bootstrap_code()),
             function_name,
         );
-        let mut actual = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut actual)?;
+        let actual = output(&code_writer);
 
         assert_eq!(normalize(&expect), normalize(&actual));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 
     #[test]
     fn test_write_function_code_when_args_zero() -> Result<()> {
-        let (mut code_writer, test_file_name) = get_code_writer()?;
+        let mut code_writer = get_code_writer()?;
         let (function_name, n_args) = ("SimpleFunction.test", 0);
         code_writer.write_function(function_name, n_args)?;
 
@@ -1008,22 +1602,799 @@ M=M+1
 {}
 ({})
 ",
-            code_writer.get_bootstrap_code(),
+            asm::render(&code_writer.get_bootstrap_code()),
             function_name,
         );
-        let mut actual = String::new();
-        File::open(&test_file_name)?.read_to_string(&mut actual)?;
+        let actual = output(&code_writer);
 
         assert_eq!(normalize(&expect), normalize(&actual));
+        Ok(())
+    }
 
-        fs::remove_file(test_file_name)?;
+    #[test]
+    fn test_compact_locals() -> Result<()> {
+        let compact = CodeWriterOptions {
+            compact_locals_threshold: Some(0),
+            ..Default::default()
+        };
+        assert_same_behavior(compact.clone())?;
+
+        // (ローカル変数の数, 選ばれる形の命令数)。展開すると1個あたり7命令、M=0の並びは2n+4命令、ループは9命令
+        for (n_vars, expected_count) in [(1, 6), (2, 8), (3, 9), (40, 9)] {
+            let mut code_writer = CodeWriter::with_options(Vec::new(), "Main", compact.clone())?;
+            let function_code = code_writer.get_function_code("Main.f", n_vars);
+            let count = function_code
+                .iter()
+                .filter(|instr| instr.is_instruction())
+                .count();
+            assert_eq!(count, expected_count, "{} locals", n_vars);
+
+            // 以前に使われたスタックの領域にゴミが残っていても、ローカル変数は0で始まる
+            let mut vm_code = format!("function Main.f {}\npush constant 1\n", n_vars);
+            for i in 0..n_vars {
+                vm_code += &format!("push local {}\nadd\n", i);
+            }
+            vm_code += "return\nfunction Sys.init 0\n";
+            vm_code += &"push constant 9\n".repeat(50);
+            vm_code += &"pop temp 0\n".repeat(50);
+            vm_code += "call Main.f 0\npop static 0\n";
+            let emulator = run_vm_code_with_options(&vm_code, compact.clone())?;
+            assert_eq!(emulator.ram[16], 1, "{} locals", n_vars);
+            assert_eq!(emulator.ram[0], 261);
+        }
+
+        // しきい値以下の関数はこれまでどおり展開する
+        let mut code_writer = CodeWriter::with_options(
+            Vec::new(),
+            "Main",
+            CodeWriterOptions {
+                compact_locals_threshold: Some(40),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(
+            code_writer.get_function_code("Main.f", 40),
+            get_code_writer()?.get_function_code("Main.f", 40)
+        );
+
+        // 関数内のVMコードのラベルinit_localsとループのラベルは別のシンボルになる
+        let vm_code = "function Main.f 5\nlabel init_locals\npush constant 0\nreturn\n";
+        let code_writer = write_vm_code("Main.vm", vm_code, compact)?;
+        assembler::assemble(&String::from_utf8(code_writer.close()?)?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_routine_labels_do_not_collide_with_vm_labels() -> Result<()> {
+        // 共通処理やブートストラップのラベルと同じ綴りになるvmのラベルを書いても重複しない
+        for (vm_filename, vm_code, options) in [
+            (
+                "Main.vm",
+                "function VM 0\nlabel CALL\ncall VM 0\nreturn",
+                CodeWriterOptions {
+                    shared_call_return: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "VM.vm",
+                "label BOOTSTRAP_RET\ngoto BOOTSTRAP_RET",
+                CodeWriterOptions {
+                    bootstrap: Bootstrap::On,
+                    ..Default::default()
+                },
+            ),
+        ] {
+            let code_writer = write_vm_code(vm_filename, vm_code, options)?;
+            assembler::assemble(&String::from_utf8(code_writer.close()?)?)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unique_labels_qualified_by_filename() -> Result<()> {
+        // 別々に翻訳したファイルを連結してもラベルが衝突しない
+        let labels = |vm_filename: &str| -> Result<Vec<String>> {
+            let mut code_writer = CodeWriter::with_options(
+                Vec::new(),
+                "Test",
+                CodeWriterOptions {
+                    bootstrap: Bootstrap::Off,
+                    ..Default::default()
+                },
+            )?;
+            code_writer.set_filename(Path::new(vm_filename))?;
+            code_writer.write_arithmetic(ArithOp::Eq)?;
+            code_writer.write_call("Main.f", 0)?;
+            Ok(code_writer
+                .instructions
+                .iter()
+                .filter_map(|instr| match instr {
+                    AsmInstr::Label(label) => Some(label.clone()),
+                    _ => None,
+                })
+                .collect())
+        };
+        let main_labels = labels("Main.vm")?;
+        let other_labels = labels("Other.vm")?;
+        assert!(main_labels.contains(&"Main$ret$1".to_string()));
+        assert!(main_labels.iter().all(|label| label.starts_with("Main$")));
+        assert!(other_labels.iter().all(|label| label.starts_with("Other$")));
+
+        // 生成したラベルと同じ綴りのvmのラベルを書いても重複しない
+        let code_writer = write_vm_code(
+            "Main.vm",
+            "label cmp.0.true\npush constant 1\npush constant 1\neq\ngoto cmp.0.true",
+            CodeWriterOptions::default(),
+        )?;
+        assembler::assemble(&String::from_utf8(code_writer.close()?)?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_link() -> Result<()> {
+        // 共通処理を使う設定で別々にオブジェクトにしても、リンクで共通処理が加わる
+        let compile = |vm_filename: &str, vm_code: &str| -> Result<Object> {
+            let options = CodeWriterOptions {
+                bootstrap: Bootstrap::Off,
+                shared_call_return: true,
+                shared_comparisons: true,
+                ..Default::default()
+            };
+            write_vm_code(vm_filename, vm_code, options)?.into_object()
+        };
+        let sys = compile(
+            "Sys.vm",
+            "function Sys.init 0
+            push constant 21
+            call Main.double 1
+            pop static 0
+            push constant 1
+            push constant 1
+            eq
+            pop static 1
+            label LOOP
+            goto LOOP",
+        )?;
+        let main = compile(
+            "Main.vm",
+            "function Main.double 0
+            push argument 0
+            push argument 0
+            add
+            return",
+        )?;
+        assert_eq!(sys.exports, ["Sys.init"]);
+        assert_eq!(sys.imports, ["Main.double"]);
+        assert_eq!(main.exports, ["Main.double"]);
+        assert!(main.imports.is_empty());
+        assert!(!sys.instructions.contains(&AsmInstr::label(CALL_ROUTINE)));
+
+        let mut code_writer = get_code_writer()?;
+        code_writer.write_object(&sys)?;
+        code_writer.write_object(&main)?;
+        let asm = String::from_utf8(code_writer.close()?)?;
+        let mut emulator = Emulator::new(&asm)?;
+        assert!(emulator.run(100_000));
+        assert_eq!(emulator.ram[16], 42);
+        assert_eq!(emulator.ram[17], -1);
+
+        assert!(get_code_writer()?.into_object().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_label_scoped_by_function() -> Result<()> {
+        let mut code_writer = get_code_writer()?;
+        code_writer.set_filename(Path::new("Main.vm"))?;
+        code_writer.write_label("LOOP")?;
+        code_writer.write_function("Main.a", 0)?;
+        code_writer.write_label("LOOP")?;
+        code_writer.write_goto("LOOP")?;
+        code_writer.write_function("Main.b", 0)?;
+        code_writer.write_label("LOOP")?;
+        code_writer.write_if("LOOP")?;
+
+        let actual = output(&code_writer);
+
+        let expect = format!(
+            "{}
+(Main$LOOP)
+(Main.a)
+(Main.a$LOOP)
+@Main.a$LOOP
+0;JMP
+(Main.b)
+(Main.b$LOOP)
+// if-goto Main.b$LOOP
+{}
+@Main.b$LOOP
+D;JNE
+",
+            asm::render(&code_writer.get_bootstrap_code()),
+            asm::render(&code_writer.get_pop_code()),
+        );
+        assert_eq!(normalize(&expect), normalize(&actual));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_label_when_raw_outside_function() -> Result<()> {
+        let mut code_writer = CodeWriter::with_options(
+            Vec::new(),
+            "Test",
+            CodeWriterOptions {
+                raw_labels_outside_function: true,
+                ..Default::default()
+            },
+        )?;
+        code_writer.write_label("LOOP")?;
+        code_writer.write_function("Main.a", 0)?;
+        code_writer.write_label("LOOP")?;
+
+        let actual = output(&code_writer);
+
+        let expect = format!(
+            "{}
+(LOOP)
+(Main.a)
+(Main.a$LOOP)
+",
+            asm::render(&code_writer.get_bootstrap_code()),
+        );
+        assert_eq!(normalize(&expect), normalize(&actual));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_comment() -> Result<()> {
+        let mut code_writer = get_code_writer()?;
+        code_writer.write_comment("load x")?;
+
+        let actual = output(&code_writer);
+
+        let expect = format!(
+            "{}// load x\n",
+            asm::render(&code_writer.get_bootstrap_code())
+        );
+        assert_eq!(expect, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootstrap_options() -> Result<()> {
+        let code_writer = CodeWriter::with_options(
+            Vec::new(),
+            "Test",
+            CodeWriterOptions {
+                bootstrap: Bootstrap::Off,
+                ..Default::default()
+            },
+        )?;
+        let actual = String::from_utf8(code_writer.close()?)?;
+        assert_eq!(normalize("(END)\n@END\n0;JMP"), normalize(&actual));
+
+        let code_writer = CodeWriter::with_options(
+            Vec::new(),
+            "Test",
+            CodeWriterOptions {
+                bootstrap: Bootstrap::On,
+                initial_sp: 261,
+                segment_bases: SegmentBases {
+                    local: 1000,
+                    argument: 2000,
+                    this: 3000,
+                    that: 4000,
+                },
+                ..Default::default()
+            },
+        )?;
+        let bootstrap_code = normalize(&asm::render(&code_writer.get_bootstrap_code()));
+        assert!(bootstrap_code.starts_with(&normalize(
+            "// bootstrap
+            @261
+            D=A
+            @SP
+            M=D
+            @1000
+            D=A
+            @LCL
+            M=D
+            @2000
+            D=A
+            @ARG
+            M=D
+            @3000
+            D=A
+            @THIS
+            M=D
+            @4000
+            D=A
+            @THAT
+            M=D
+            // call function Sys.init"
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_return_keeps_statics() -> Result<()> {
+        let emulator = run_vm_code(
+            "
+            function Main.seven 0
+            push constant 7
+            return
+            function Sys.init 0
+            push constant 42
+            pop static 0
+            call Main.seven 0
+            pop static 1
+            ",
+        )?;
+
+        // static変数はRAM[16]から順に割り当てられ、returnで上書きされない
+        assert_eq!(emulator.ram[16], 42);
+        assert_eq!(emulator.ram[17], 7);
+        assert_eq!(emulator.ram[0], 261);
+        Ok(())
+    }
+
+    /// 関数呼び出し、ループ、比較、全セグメントを使うプログラム
+    const OPTIMIZE_TEST_VM_CODE: &str = "
+        function Main.mix 1
+        push argument 0
+        push argument 1
+        sub
+        pop local 0
+        push local 0
+        push local 0
+        add
+        pop this 2
+        push argument 0
+        push argument 1
+        gt
+        push argument 1
+        push argument 0
+        lt
+        and
+        push argument 0
+        push argument 0
+        eq
+        or
+        not
+        pop that 1
+        push local 0
+        neg
+        pop temp 3
+        push this 2
+        return
+        function Main.sum 1
+        label LOOP
+        push argument 0
+        if-goto BODY
+        push local 0
+        return
+        label BODY
+        push local 0
+        push argument 0
+        add
+        pop local 0
+        push argument 0
+        push constant 1
+        sub
+        pop argument 0
+        goto LOOP
+        function Sys.init 0
+        push constant 3000
+        pop pointer 0
+        push constant 4000
+        pop pointer 1
+        push constant 10
+        push constant 3
+        call Main.mix 2
+        pop static 0
+        push constant 5
+        call Main.sum 1
+        pop static 1
+        ";
+
+    /// 既定の設定で翻訳した場合と同じ結果になることをエミュレータで確かめる
+    fn assert_same_behavior(options: CodeWriterOptions) -> Result<()> {
+        let plain = run_vm_code(OPTIMIZE_TEST_VM_CODE)?;
+        let actual = run_vm_code_with_options(OPTIMIZE_TEST_VM_CODE, options)?;
+
+        assert_eq!(plain.ram[16], 14);
+        assert_eq!(plain.ram[17], 15);
+        assert_eq!(plain.ram[3002], 14);
+        assert_eq!(plain.ram[4001], 0);
+        assert_eq!(plain.ram[8], -7);
+        // スタックより上の領域は使い終わった値が残るだけなので比較しない。
+        // RAM[256~260]はSys.initのフレームで、リターンアドレスはコードの配置で変わる
+        let sp = plain.ram[0] as usize;
+        for range in [0..13, 16..256, 257..sp, 3000..3010, 4000..4010] {
+            assert_eq!(plain.ram[range.clone()], actual.ram[range]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimize_keeps_behavior() -> Result<()> {
+        assert_same_behavior(CodeWriterOptions {
+            optimize: true,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_shared_call_return() -> Result<()> {
+        let shared = CodeWriterOptions {
+            shared_call_return: true,
+            ..Default::default()
+        };
+        assert_same_behavior(shared.clone())?;
+        assert_same_behavior(CodeWriterOptions {
+            optimize: true,
+            ..shared.clone()
+        })?;
+
+        let translate = |options: CodeWriterOptions| -> Result<String> {
+            let code_writer = write_vm_code("Main.vm", OPTIMIZE_TEST_VM_CODE, options)?;
+            Ok(String::from_utf8(code_writer.close()?)?)
+        };
+        let inline_asm = translate(CodeWriterOptions::default())?;
+        let shared_asm = translate(shared.clone())?;
+        assert!(shared_asm.lines().count() < inline_asm.lines().count());
+        assert_eq!(shared_asm.matches("(VM$$CALL)").count(), 1);
+        assert_eq!(shared_asm.matches("(VM$$RETURN)").count(), 1);
+
+        // 呼ばれない共通処理は出力しない
+        let code_writer = CodeWriter::with_options(
+            Vec::new(),
+            "Main",
+            CodeWriterOptions {
+                bootstrap: Bootstrap::Off,
+                ..shared
+            },
+        )?;
+        assert!(!String::from_utf8(code_writer.close()?)?.contains("VM$$"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimize_report() -> Result<()> {
+        let options = CodeWriterOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        let code_writer = write_vm_code("Main.vm", OPTIMIZE_TEST_VM_CODE, options)?;
+        let (asm, report) = code_writer.close_with_report()?;
+
+        let report = report.optimization.unwrap();
+        assert!(report.after < report.before);
+        // 生成したラベルが重複せず、そのままアセンブルできる
+        let program = assembler::assemble(&String::from_utf8(asm)?)?;
+        assert_eq!(program.words.len(), report.after);
+        Ok(())
+    }
+
+    #[test]
+    fn test_comparison_overflow() -> Result<()> {
+        // 差があふれる組み合わせを含めて、比較結果をstatic変数に順に書き込む
+        let vm_code = "
+            function Sys.init 0
+            push constant 32767
+            push constant 2
+            neg
+            gt
+            pop static 0
+            push constant 2
+            neg
+            push constant 32767
+            lt
+            pop static 1
+            push constant 32767
+            neg
+            push constant 1
+            sub
+            pop static 10
+            push static 10
+            push constant 1
+            lt
+            pop static 2
+            push static 10
+            push constant 1
+            gt
+            pop static 3
+            push constant 32767
+            push constant 2
+            neg
+            lt
+            pop static 4
+            push constant 3
+            push constant 5
+            gt
+            pop static 5
+            push constant 3
+            neg
+            push constant 2
+            neg
+            lt
+            pop static 6
+            push static 10
+            push constant 32767
+            eq
+            pop static 7
+            push constant 7
+            push constant 7
+            eq
+            pop static 8
+            push constant 5
+            push constant 5
+            gt
+            pop static 9
+            ";
+        // static変数は最初に現れた順にRAM[16]から割り当てられるので、static 10はRAM[18]になる
+        let expected = [-1, -1, -32768, -1, 0, 0, 0, -1, 0, -1, 0];
+
+        for options in [
+            CodeWriterOptions::default(),
+            CodeWriterOptions {
+                shared_comparisons: true,
+                ..Default::default()
+            },
+            CodeWriterOptions {
+                shared_comparisons: true,
+                optimize: true,
+                ..Default::default()
+            },
+        ] {
+            let emulator = run_vm_code_with_options(vm_code, options.clone())?;
+            assert_eq!(emulator.ram[16..27], expected, "{:?}", options);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_comparisons() -> Result<()> {
+        assert_same_behavior(CodeWriterOptions {
+            shared_comparisons: true,
+            ..Default::default()
+        })?;
+
+        let mut code_writer = CodeWriter::with_options(
+            Vec::new(),
+            "Main",
+            CodeWriterOptions {
+                bootstrap: Bootstrap::Off,
+                shared_comparisons: true,
+                ..Default::default()
+            },
+        )?;
+        for command in [ArithOp::Eq, ArithOp::Lt, ArithOp::Eq] {
+            code_writer.write_arithmetic(command)?;
+        }
+        let asm = String::from_utf8(code_writer.close()?)?;
+        assert_eq!(asm.matches("(VM$$EQ)").count(), 1);
+        assert_eq!(asm.matches("(VM$$CMP_TRUE)").count(), 1);
+        assert!(!asm.contains("(TRUE"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_top_of_stack() -> Result<()> {
+        let cache = CodeWriterOptions {
+            cache_top_of_stack: true,
+            ..Default::default()
+        };
+        assert_same_behavior(cache.clone())?;
+        assert_same_behavior(CodeWriterOptions {
+            optimize: true,
+            shared_call_return: true,
+            shared_comparisons: true,
+            ..cache.clone()
+        })?;
+
+        // push constant 8の値はメモリを経由せずaddに渡る
+        let mut code_writer = CodeWriter::with_options(
+            Vec::new(),
+            "Main",
+            CodeWriterOptions {
+                bootstrap: Bootstrap::Off,
+                ..cache
+            },
+        )?;
+        code_writer.write_push_pop(CommandType::Push, Segment::Constant, 7)?;
+        code_writer.write_push_pop(CommandType::Push, Segment::Constant, 8)?;
+        code_writer.write_arithmetic(ArithOp::Add)?;
+        code_writer.write_push_pop(CommandType::Pop, Segment::Static, 0)?;
+        code_writer.write_label("LOOP")?;
+        let expect = "
+            // constant 7
+            @7
+            D=A
+            // push
+            @SP
+            A=M
+            M=D
+            @SP
+            M=M+1
+            // constant 8
+            @8
+            D=A
+            @R13
+            M=D
+            // pop
+            @SP
+            M=M-1
+            A=M
+            D=M
+            @R13
+            // add
+            D=D+M
+            // static 0
+            @Main.0
+            M=D
+            (Main$LOOP)
+            ";
+        assert_eq!(normalize(expect), normalize(&output(&code_writer)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_specialize_push_pop() -> Result<()> {
+        let specialize = CodeWriterOptions {
+            specialize_push_pop: true,
+            ..Default::default()
+        };
+        let with_cache = CodeWriterOptions {
+            cache_top_of_stack: true,
+            ..specialize.clone()
+        };
+        assert_same_behavior(specialize.clone())?;
+        assert_same_behavior(with_cache.clone())?;
+
+        // 専用の形を使うインデックスの境界をまたいで、各セグメントに書き込んで読み戻す
+        let mut vm_code = String::from("function Main.run 8\n");
+        for (n, segment) in ["local", "argument", "this", "that", "temp"]
+            .iter()
+            .enumerate()
+        {
+            for i in 0..8 {
+                vm_code += &format!("push constant {}\npop {} {}\n", n * 10 + i, segment, i);
+            }
+            vm_code += "push constant 0\n";
+            for i in 0..8 {
+                vm_code += &format!("push {} {}\nadd\n", segment, i);
+            }
+            vm_code += &format!("pop static {}\n", n);
+        }
+        // -1の書き込みにまとめる組と、まとめずに計算する組
+        vm_code += "push constant 1\nneg\npush constant 0\nnot\nadd\npop static 6\n";
+        vm_code += "push constant 0\nneg\npush constant 1\nnot\nadd\npop static 7\n";
+        vm_code += "push constant 1\nreturn\nfunction Sys.init 0\n";
+        vm_code += "push constant 3000\npop pointer 0\npush constant 4000\npop pointer 1\n";
+        vm_code += &"push constant 0\n".repeat(8);
+        vm_code += "call Main.run 8\npop static 5\n";
+
+        let plain = run_vm_code(&vm_code)?;
+        assert_eq!(plain.ram[16..24], [28, 108, 188, 268, 348, -2, -2, 1]);
+        for options in [specialize, with_cache] {
+            let actual = run_vm_code_with_options(&vm_code, options.clone())?;
+            let sp = plain.ram[0] as usize;
+            for range in [0..13, 16..256, 261..sp, 3000..3010, 4000..4010] {
+                assert_eq!(plain.ram[range.clone()], actual.ram[range], "{:?}", options);
+            }
+        }
+
+        let mut code_writer = CodeWriter::with_options(
+            Vec::new(),
+            "Main",
+            CodeWriterOptions {
+                bootstrap: Bootstrap::Off,
+                specialize_push_pop: true,
+                ..Default::default()
+            },
+        )?;
+        code_writer.write_push_pop(CommandType::Push, Segment::Constant, 1)?;
+        code_writer.write_arithmetic(ArithOp::Neg)?;
+        code_writer.write_push_pop(CommandType::Push, Segment::Local, 0)?;
+        code_writer.write_push_pop(CommandType::Pop, Segment::That, 2)?;
+        let expect = "
+            // constant 1
+            @SP
+            M=M+1
+            A=M-1
+            M=-1
+            // local 0
+            @LCL
+            A=M
+            D=M
+            // push
+            @SP
+            A=M
+            M=D
+            @SP
+            M=M+1
+            // pop
+            @SP
+            M=M-1
+            A=M
+            D=M
+            // that 2
+            @THAT
+            A=M+1
+            A=A+1
+            M=D
+            ";
+        assert_eq!(normalize(expect), normalize(&output(&code_writer)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_style() -> Result<()> {
+        for output_style in [OutputStyle::Annotated, OutputStyle::Stripped] {
+            assert_same_behavior(CodeWriterOptions {
+                output_style,
+                ..Default::default()
+            })?;
+        }
+
+        let translate = |output_style: OutputStyle| -> Result<String> {
+            let mut code_writer = CodeWriter::with_options(
+                Vec::new(),
+                "Main",
+                CodeWriterOptions {
+                    bootstrap: Bootstrap::Off,
+                    output_style,
+                    optimize: true,
+                    ..Default::default()
+                },
+            )?;
+            code_writer.set_source_location(SourceLocation {
+                vm_file: "Main.vm".to_string(),
+                line: 1,
+                command: "push constant 7".to_string(),
+            });
+            code_writer.write_command(&Command::Push {
+                segment: Segment::Constant,
+                index: 7,
+            })?;
+            code_writer.set_source_location(SourceLocation {
+                vm_file: "Main.vm".to_string(),
+                line: 2,
+                command: "pop static 0".to_string(),
+            });
+            code_writer.write_command(&Command::Pop {
+                segment: Segment::Static,
+                index: 0,
+            })?;
+            Ok(String::from_utf8(code_writer.close()?)?)
+        };
+
+        let annotated = translate(OutputStyle::Annotated)?;
+        assert!(annotated.starts_with("// Main.vm:1: push constant 7\n// constant 7\n@7\n"));
+        assert!(annotated.contains("// Main.vm:2: pop static 0\n"));
+
+        let plain = translate(OutputStyle::Plain)?;
+        assert!(plain.starts_with("// constant 7\n@7\n"));
+
+        let stripped = translate(OutputStyle::Stripped)?;
+        assert!(stripped.lines().all(|line| !line.starts_with("//")));
+        let code_lines = |asm: &str| -> Vec<String> {
+            asm.lines()
+                .filter(|line| !line.starts_with("//"))
+                .map(str::to_string)
+                .collect()
+        };
+        assert_eq!(code_lines(&stripped), code_lines(&plain));
+        assert_eq!(code_lines(&stripped), code_lines(&annotated));
         Ok(())
     }
 
     #[test]
     fn test_write_infinity_loop() -> Result<()> {
-        let (code_writer, test_file_name) = get_code_writer()?;
-        let asm_file_content = code_writer.get_infinity_loop_code();
+        let code_writer = get_code_writer()?;
+        let asm_file_content = asm::render(&code_writer.get_infinity_loop_code());
 
         let expect_asm = "
         (END)
@@ -1031,8 +2402,6 @@ M=M+1
         0;JMP
         ";
         assert_eq!(normalize(expect_asm), normalize(&asm_file_content));
-
-        fs::remove_file(test_file_name)?;
         Ok(())
     }
 }
@@ -0,0 +1,133 @@
+//! 1つのvmファイルを翻訳した再配置可能なオブジェクト。
+//! ブートストラップ、終了の無限ループ、共通処理を含まず、リンクでまとめて1つのプログラムにする
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context, Error, Result};
+
+use crate::asm::{self, AsmInstr};
+
+const MODULE_DIRECTIVE: &str = ".object";
+const EXPORT_DIRECTIVE: &str = ".export";
+const IMPORT_DIRECTIVE: &str = ".import";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    /// 翻訳したvmファイルの名前(拡張子を除く)
+    pub module: String,
+    /// このオブジェクトで定義した関数。定義した順
+    pub exports: Vec<String>,
+    /// 呼び出しているが、このオブジェクトでは定義していない関数。最初に呼び出した順
+    pub imports: Vec<String>,
+    pub instructions: Vec<AsmInstr>,
+}
+
+/// `.object`、`.export`、`.import`の行を並べた見出しの後に、1行1命令でアセンブリを書く
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", MODULE_DIRECTIVE, self.module)?;
+        for export in &self.exports {
+            writeln!(f, "{} {}", EXPORT_DIRECTIVE, export)?;
+        }
+        for import in &self.imports {
+            writeln!(f, "{} {}", IMPORT_DIRECTIVE, import)?;
+        }
+        write!(f, "{}", asm::render(&self.instructions))
+    }
+}
+
+impl FromStr for Object {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut module = None;
+        let mut exports = Vec::new();
+        let mut imports = Vec::new();
+        let mut instructions = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            // アセンブリの行は`.`で始まらないので見出しと区別できる
+            if !line.starts_with('.') {
+                let instr = line
+                    .parse()
+                    .map_err(Error::msg)
+                    .with_context(|| format!("<object>:{}: invalid instruction", index + 1))?;
+                instructions.push(instr);
+                continue;
+            }
+            if !instructions.is_empty() {
+                bail!("<object>:{}: directive after instructions", index + 1);
+            }
+            let Some((directive, name)) = line.split_once(' ') else {
+                bail!("<object>:{}: missing name in `{}`", index + 1, line);
+            };
+            match directive {
+                MODULE_DIRECTIVE if module.is_none() => module = Some(name.to_string()),
+                EXPORT_DIRECTIVE => exports.push(name.to_string()),
+                IMPORT_DIRECTIVE => imports.push(name.to_string()),
+                _ => bail!(
+                    "<object>:{}: unexpected directive `{}`",
+                    index + 1,
+                    directive
+                ),
+            }
+        }
+        Ok(Self {
+            module: module.context("missing .object directive")?,
+            exports,
+            imports,
+            instructions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_text() -> Result<()> {
+        let object = Object {
+            module: "Main".to_string(),
+            exports: vec!["Main.main".to_string()],
+            imports: vec!["Math.multiply".to_string(), "Sys.halt".to_string()],
+            instructions: "(Main.main)\n// call function Math.multiply\n@Math.multiply\n0;JMP"
+                .lines()
+                .map(|line| line.parse().unwrap())
+                .collect(),
+        };
+        let text = object.to_string();
+        assert_eq!(
+            text,
+            ".object Main
+.export Main.main
+.import Math.multiply
+.import Sys.halt
+(Main.main)
+// call function Math.multiply
+@Math.multiply
+0;JMP
+"
+        );
+        assert_eq!(text.parse::<Object>()?, object);
+
+        for (text, message) in [
+            (".export Main.main\n", "missing .object directive"),
+            (
+                ".object Main\n.object Other\n",
+                "<object>:2: unexpected directive `.object`",
+            ),
+            (
+                ".object Main\n@1\n.export Main.f\n",
+                "<object>:3: directive after instructions",
+            ),
+            (".object\n", "<object>:1: missing name in `.object`"),
+            (".object Main\nD=D+2\n", "<object>:2: invalid instruction"),
+        ] {
+            assert_eq!(text.parse::<Object>().unwrap_err().to_string(), message);
+        }
+        Ok(())
+    }
+}
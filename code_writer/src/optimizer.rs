@@ -0,0 +1,348 @@
+//! 生成した命令列に対するのぞき穴最適化。
+//! VMコードの意味を変えない範囲で、スタック操作の冗長な命令を取り除く
+use std::fmt;
+
+use crate::asm::{Address, AsmInstr, Comp, Dest};
+
+/// 最適化の前後でROMに配置される命令数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptimizeReport {
+    pub before: usize,
+    pub after: usize,
+}
+
+impl OptimizeReport {
+    pub fn saved(&self) -> usize {
+        self.before - self.after
+    }
+}
+
+impl fmt::Display for OptimizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} instructions ({} saved)",
+            self.before,
+            self.after,
+            self.saved()
+        )
+    }
+}
+
+/// 命令列を書き換え、取り除く命令に印を付けて返す書き換えの1パターン
+type Pass = fn(&mut [AsmInstr]) -> Vec<bool>;
+
+/// 変化が無くなるまで各パターンの書き換えを繰り返す
+pub fn optimize(instrs: &mut Vec<AsmInstr>) -> OptimizeReport {
+    optimize_with_tags(instrs, &mut vec![(); instrs.len()])
+}
+
+/// optimizeと同じだが、命令ごとの付加情報(生成元のVMコマンドなど)のtagsも命令と一緒に取り除く。
+/// 2つの命令をまとめた場合は前の命令のtagを残す
+pub fn optimize_with_tags<T>(instrs: &mut Vec<AsmInstr>, tags: &mut Vec<T>) -> OptimizeReport {
+    assert_eq!(instrs.len(), tags.len());
+    let before = count_instructions(instrs);
+    let passes: [Pass; 5] = [
+        // push/popの組は他の書き換えで形が崩れる前に取り除く
+        remove_push_pop_pairs,
+        remove_redundant_addresses,
+        remove_reload_after_store,
+        merge_update_and_load,
+        remove_dead_d_stores,
+    ];
+    loop {
+        let mut changed = false;
+        for pass in passes {
+            let removed = pass(instrs);
+            changed |= remove_marked(instrs, tags, &removed);
+        }
+        if !changed {
+            break;
+        }
+    }
+    OptimizeReport {
+        before,
+        after: count_instructions(instrs),
+    }
+}
+
+fn count_instructions(instrs: &[AsmInstr]) -> usize {
+    instrs.iter().filter(|instr| instr.is_instruction()).count()
+}
+
+/// コメント以外の命令の位置。ラベルは飛び込んでくる経路があるので書き換えの境界として残す
+fn significant_indices(instrs: &[AsmInstr]) -> Vec<usize> {
+    (0..instrs.len())
+        .filter(|&i| !matches!(instrs[i], AsmInstr::Comment(_)))
+        .collect()
+}
+
+/// removedで印を付けた位置の命令とtagを取り除く。1つでも取り除いたらtrue
+pub(crate) fn remove_marked<T>(
+    instrs: &mut Vec<AsmInstr>,
+    tags: &mut Vec<T>,
+    removed: &[bool],
+) -> bool {
+    let mut index = 0;
+    instrs.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+    index = 0;
+    tags.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+    removed.contains(&true)
+}
+
+fn push_code() -> Vec<AsmInstr> {
+    vec![
+        AsmInstr::at("SP"),
+        AsmInstr::assign(Dest::A, Comp::M),
+        AsmInstr::assign(Dest::M, Comp::D),
+        AsmInstr::at("SP"),
+        AsmInstr::assign(Dest::M, Comp::MPlusOne),
+    ]
+}
+
+fn pop_codes() -> [Vec<AsmInstr>; 2] {
+    [
+        vec![
+            AsmInstr::at("SP"),
+            AsmInstr::assign(Dest::M, Comp::MMinusOne),
+            AsmInstr::assign(Dest::A, Comp::M),
+            AsmInstr::assign(Dest::D, Comp::M),
+        ],
+        vec![
+            AsmInstr::at("SP"),
+            AsmInstr::assign(Dest::AM, Comp::MMinusOne),
+            AsmInstr::assign(Dest::D, Comp::M),
+        ],
+    ]
+}
+
+/// Dをpushした直後にDへpopする組を取り除く。
+/// popで変わるAレジスタの値が使われないよう、次の命令がA命令の場合に限る
+fn remove_push_pop_pairs(instrs: &mut [AsmInstr]) -> Vec<bool> {
+    let indices = significant_indices(instrs);
+    let matches_at = |start: usize, code: &[AsmInstr]| {
+        start + code.len() <= indices.len()
+            && code
+                .iter()
+                .enumerate()
+                .all(|(offset, instr)| &instrs[indices[start + offset]] == instr)
+    };
+    let push = push_code();
+    let pops = pop_codes();
+
+    let mut removed = vec![false; instrs.len()];
+    let mut k = 0;
+    while k < indices.len() {
+        let pop = pops.iter().find(|pop| matches_at(k + push.len(), pop));
+        if let (true, Some(pop)) = (matches_at(k, &push), pop) {
+            let end = k + push.len() + pop.len();
+            if let Some(AsmInstr::A(_)) = indices.get(end).map(|&i| &instrs[i]) {
+                let mut start = indices[k];
+                if start > 0 && instrs[start - 1] == AsmInstr::comment("push") {
+                    start -= 1;
+                }
+                // 取り除くpush、popの説明以外のコメント(VMコードの行末コメントなど)は残す
+                let is_stack_comment = |instr: &AsmInstr| match instr {
+                    AsmInstr::Comment(text) => text == "push" || text == "pop",
+                    _ => true,
+                };
+                for i in start..=indices[end - 1] {
+                    removed[i] = is_stack_comment(&instrs[i]);
+                }
+                k = end;
+                continue;
+            }
+        }
+        k += 1;
+    }
+    removed
+}
+
+/// Aレジスタに既に入っているアドレスを読み込み直す`@xxx`を取り除く
+fn remove_redundant_addresses(instrs: &mut [AsmInstr]) -> Vec<bool> {
+    let mut known: Option<&Address> = None;
+    let mut removed = vec![false; instrs.len()];
+    for (i, instr) in instrs.iter().enumerate() {
+        match instr {
+            AsmInstr::A(address) if known == Some(address) => removed[i] = true,
+            AsmInstr::A(address) => known = Some(address),
+            AsmInstr::C {
+                dest: Some(dest), ..
+            } if dest.writes_a() => known = None,
+            AsmInstr::Label(_) => known = None,
+            _ => (),
+        }
+    }
+    removed
+}
+
+/// `M=D`の直後の`D=M`はDの値を変えないので取り除く
+fn remove_reload_after_store(instrs: &mut [AsmInstr]) -> Vec<bool> {
+    let store = AsmInstr::assign(Dest::M, Comp::D);
+    let load = AsmInstr::assign(Dest::D, Comp::M);
+    let mut removed = vec![false; instrs.len()];
+    for pair in significant_indices(instrs).windows(2) {
+        if instrs[pair[0]] == store && instrs[pair[1]] == load {
+            removed[pair[1]] = true;
+        }
+    }
+    removed
+}
+
+/// `M=M-1`(`M=M+1`)と直後の`A=M`を`AM=M-1`(`AM=M+1`)にまとめる
+fn merge_update_and_load(instrs: &mut [AsmInstr]) -> Vec<bool> {
+    let load = AsmInstr::assign(Dest::A, Comp::M);
+    let mut removed = vec![false; instrs.len()];
+    for pair in significant_indices(instrs).windows(2) {
+        let AsmInstr::C {
+            dest: Some(Dest::M),
+            comp: comp @ (Comp::MMinusOne | Comp::MPlusOne),
+            jump: None,
+        } = instrs[pair[0]]
+        else {
+            continue;
+        };
+        if instrs[pair[1]] == load && !removed[pair[0]] {
+            instrs[pair[0]] = AsmInstr::assign(Dest::AM, comp);
+            removed[pair[1]] = true;
+        }
+    }
+    removed
+}
+
+/// 読まれる前に上書きされるDへの代入を取り除く。
+/// ラベルやジャンプの先ではDが使われるかもしれないので生きているものとして扱う
+fn remove_dead_d_stores(instrs: &mut [AsmInstr]) -> Vec<bool> {
+    let mut d_live = true;
+    let mut removed = vec![false; instrs.len()];
+    for (i, instr) in instrs.iter().enumerate().rev() {
+        match instr {
+            AsmInstr::Label(_) => d_live = true,
+            AsmInstr::C { dest, comp, jump } => {
+                if jump.is_some() {
+                    d_live = true;
+                } else if *dest == Some(Dest::D) && !d_live {
+                    removed[i] = true;
+                    continue;
+                }
+                let writes_d = dest.is_some_and(|dest| dest.writes_d());
+                d_live = (d_live && !writes_d) || comp.reads_d();
+            }
+            _ => (),
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::render;
+    use pretty_assertions::assert_eq;
+
+    fn parse(asm: &str) -> Vec<AsmInstr> {
+        asm.split_whitespace()
+            .map(|line| line.parse().unwrap())
+            .collect()
+    }
+
+    fn optimized(asm: &str) -> String {
+        let mut instrs = parse(asm);
+        optimize(&mut instrs);
+        render(&instrs)
+    }
+
+    #[test]
+    fn test_remove_push_pop_pairs() {
+        let push_pop = "@SP A=M M=D @SP M=M+1 @SP M=M-1 A=M D=M";
+        assert_eq!(
+            optimized(&format!("@7 D=A {} @R13 M=D", push_pop)),
+            render(&parse("@7 D=A @R13 M=D"))
+        );
+
+        // pushとpopの間にあるVMコードのコメントは残す
+        let mut instrs = parse(&format!("@7 D=A {} @R13 M=D", push_pop));
+        instrs.insert(2, AsmInstr::comment("push"));
+        instrs.insert(8, AsmInstr::comment("7 + 8"));
+        instrs.insert(9, AsmInstr::comment("pop"));
+        optimize(&mut instrs);
+        assert_eq!(render(&instrs), "@7\nD=A\n// 7 + 8\n@R13\nM=D\n");
+
+        // ラベルに飛び込んでくる経路があるので取り除かない
+        let asm = "@7 D=A @SP A=M M=D @SP M=M+1 (L) @SP AM=M-1 D=M @R13 M=D";
+        assert_eq!(optimized(asm), render(&parse(asm)));
+
+        // popで設定したAレジスタを次の命令が使う
+        assert_eq!(
+            optimized("@SP A=M M=D @SP M=M+1 @SP AM=M-1 D=M M=D"),
+            render(&parse("@SP A=M M=D @SP M=M+1 AM=M-1 D=M M=D"))
+        );
+    }
+
+    #[test]
+    fn test_remove_redundant_addresses() {
+        assert_eq!(
+            optimized("@SP M=M+1 @SP A=M M=D @SP M=M+1"),
+            render(&parse("@SP AM=M+1 M=D @SP M=M+1"))
+        );
+        let asm = "@SP M=M+1 (L) @SP M=M+1";
+        assert_eq!(optimized(asm), render(&parse(asm)));
+    }
+
+    #[test]
+    fn test_merge_and_reload() {
+        assert_eq!(
+            optimized("@SP M=M-1 A=M D=M @Main.0 M=D @Main.0 D=M @R13 M=D"),
+            render(&parse("@SP AM=M-1 D=M @Main.0 M=D @R13 M=D"))
+        );
+    }
+
+    #[test]
+    fn test_remove_dead_d_stores() {
+        assert_eq!(
+            optimized("D=A @5 D=M @R13 M=D"),
+            render(&parse("@5 D=M @R13 M=D"))
+        );
+        // ジャンプ先でDが使われるかもしれない
+        let asm = "D=A @L 0;JMP";
+        assert_eq!(optimized(asm), render(&parse(asm)));
+    }
+
+    #[test]
+    fn test_optimize_with_tags() {
+        let mut instrs = parse("@SP A=M M=D @SP M=M+1 @SP M=M-1 A=M D=M @R13 M=D");
+        let mut tags = (0..instrs.len()).collect::<Vec<_>>();
+        optimize_with_tags(&mut instrs, &mut tags);
+        assert_eq!(render(&instrs), "@R13\nM=D\n");
+        assert_eq!(tags, [9, 10]);
+
+        // まとめた命令には前の命令のtagが残る
+        let mut instrs = parse("@SP M=M+1 A=M M=D");
+        let mut tags = vec!['a', 'b', 'c', 'd'];
+        optimize_with_tags(&mut instrs, &mut tags);
+        assert_eq!(render(&instrs), "@SP\nAM=M+1\nM=D\n");
+        assert_eq!(tags, ['a', 'b', 'd']);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut instrs = parse("@SP A=M M=D @SP M=M+1 @SP M=M-1 A=M D=M @R13 M=D");
+        instrs.insert(0, AsmInstr::comment("push"));
+        let report = optimize(&mut instrs);
+        assert_eq!(
+            report,
+            OptimizeReport {
+                before: 11,
+                after: 2
+            }
+        );
+        assert_eq!(report.saved(), 9);
+        assert_eq!(render(&instrs), "@R13\nM=D\n");
+    }
+}
@@ -0,0 +1,124 @@
+use strum_macros::{AsRefStr, EnumString};
+
+/// ブートストラップコードを出力するかどうか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, AsRefStr, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Bootstrap {
+    On,
+    Off,
+    /// Sys.init関数が入力に含まれる場合だけ出力する。
+    /// CodeWriterは入力全体を知らないので、resolveされずに渡された場合はOnとして扱う
+    #[default]
+    Auto,
+}
+
+impl Bootstrap {
+    /// AutoをSys.init関数の有無に応じてOnかOffに決める
+    pub fn resolve(self, has_sys_init: bool) -> Self {
+        match self {
+            Bootstrap::Auto if has_sys_init => Bootstrap::On,
+            Bootstrap::Auto => Bootstrap::Off,
+            bootstrap => bootstrap,
+        }
+    }
+}
+
+/// 出力するアセンブリのコメントの量
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, AsRefStr, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum OutputStyle {
+    /// 各VMコマンドの展開の前に、CodeWriter::set_source_locationで指定したコマンドと位置をコメントとして書く
+    Annotated,
+    /// `// push`や`// local 3`などの定型のコメントを書く
+    #[default]
+    Plain,
+    /// コメントを一切書かない。行数はROMの命令数とラベルの数の和になる
+    Stripped,
+}
+
+/// ブートストラップコードで設定する各セグメントのベースアドレス
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentBases {
+    pub local: u16,
+    pub argument: u16,
+    pub this: u16,
+    pub that: u16,
+}
+
+impl Default for SegmentBases {
+    fn default() -> Self {
+        Self {
+            local: 300,
+            argument: 400,
+            this: 500,
+            that: 600,
+        }
+    }
+}
+
+/// CodeWriterが生成するアセンブリの設定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeWriterOptions {
+    /// 関数の外にあるラベルをファイル名でスコープせず、VMコードに書かれた名前のまま出力する
+    pub raw_labels_outside_function: bool,
+    pub bootstrap: Bootstrap,
+    /// ブートストラップコードで設定するSPの初期値
+    pub initial_sp: u16,
+    pub segment_bases: SegmentBases,
+    /// 生成した命令列にのぞき穴最適化をかけてから書き出す
+    pub optimize: bool,
+    /// callとreturnを呼び出し箇所ごとに展開せず、プログラム全体で1つの共通処理にジャンプする
+    pub shared_call_return: bool,
+    /// eq、gt、ltを比較ごとに展開せず、プログラム全体で1つの共通処理にジャンプする
+    pub shared_comparisons: bool,
+    /// スタックの先頭の値をコマンドをまたいでDレジスタに持ち、ラベル、call、returnの前でだけメモリに書き戻す
+    pub cache_top_of_stack: bool,
+    /// `push constant 0`や`pop local 0`のようなよく使うpush、popを一般形より短い専用の形で出力する。
+    /// `push constant 1`と`neg`のように-1を積む組は-1の書き込みにまとめる
+    pub specialize_push_pop: bool,
+    /// ローカル変数の数がこの値を超える関数では、0のpushを展開した形、M=0の並び、ループのうち最も短い形で初期化する
+    pub compact_locals_threshold: Option<u16>,
+    pub output_style: OutputStyle,
+}
+
+impl Default for CodeWriterOptions {
+    fn default() -> Self {
+        Self {
+            raw_labels_outside_function: false,
+            bootstrap: Bootstrap::default(),
+            initial_sp: 256,
+            segment_bases: SegmentBases::default(),
+            optimize: false,
+            shared_call_return: false,
+            shared_comparisons: false,
+            cache_top_of_stack: false,
+            specialize_push_pop: false,
+            compact_locals_threshold: None,
+            output_style: OutputStyle::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bootstrap_resolve() {
+        assert_eq!(Bootstrap::Auto.resolve(true), Bootstrap::On);
+        assert_eq!(Bootstrap::Auto.resolve(false), Bootstrap::Off);
+        assert_eq!(Bootstrap::On.resolve(false), Bootstrap::On);
+        assert_eq!(Bootstrap::Off.resolve(true), Bootstrap::Off);
+        assert_eq!("off".parse::<Bootstrap>().unwrap(), Bootstrap::Off);
+    }
+
+    #[test]
+    fn test_output_style() {
+        assert_eq!(OutputStyle::default(), OutputStyle::Plain);
+        assert_eq!(
+            "stripped".parse::<OutputStyle>().unwrap(),
+            OutputStyle::Stripped
+        );
+        assert_eq!(OutputStyle::Annotated.as_ref(), "annotated");
+    }
+}
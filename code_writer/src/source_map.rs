@@ -0,0 +1,178 @@
+//! 出力したアセンブリの各行と、その行を生成したVMコマンドの対応
+use crate::asm::AsmInstr;
+
+/// 命令を生成したVMコマンドの位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub vm_file: String,
+    /// 1始まりの行番号
+    pub line: usize,
+    /// VMコマンドの本文。行末コメントは含まない
+    pub command: String,
+}
+
+/// アセンブリの1行に対応するVMコマンド
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMapping {
+    /// 1始まりのアセンブリの行番号
+    pub asm_line: usize,
+    /// 命令が置かれるROMアドレス。ラベルとコメントの行はNone
+    pub rom_address: Option<u16>,
+    /// sourcesの添字
+    pub source: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SourceMap {
+    pub sources: Vec<SourceLocation>,
+    /// アセンブリの行番号の昇順。ブートストラップや共通処理などVMコマンドに由来しない行は含まない
+    pub mappings: Vec<SourceMapping>,
+}
+
+impl SourceMap {
+    /// 出力する命令列と、命令ごとの生成元(sourcesの添字)から対応表を作る
+    pub fn new(
+        sources: Vec<SourceLocation>,
+        instrs: &[AsmInstr],
+        instr_sources: &[Option<usize>],
+    ) -> Self {
+        let mut rom_address = 0;
+        let mut mappings = Vec::new();
+        for (index, (instr, source)) in instrs.iter().zip(instr_sources).enumerate() {
+            let instr_rom_address = instr.is_instruction().then_some(rom_address);
+            if instr.is_instruction() {
+                rom_address += 1;
+            }
+            if let Some(source) = source {
+                mappings.push(SourceMapping {
+                    asm_line: index + 1,
+                    rom_address: instr_rom_address,
+                    source: *source,
+                });
+            }
+        }
+        Self { sources, mappings }
+    }
+
+    /// ROMアドレスの命令を生成したVMコマンド
+    pub fn find_by_rom_address(&self, rom_address: u16) -> Option<&SourceLocation> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.rom_address == Some(rom_address))
+            .map(|mapping| &self.sources[mapping.source])
+    }
+
+    pub fn to_json(&self) -> String {
+        let sources = self
+            .sources
+            .iter()
+            .map(|source| {
+                format!(
+                    "    {{\"file\": {}, \"line\": {}, \"command\": {}}}",
+                    json_string(&source.vm_file),
+                    source.line,
+                    json_string(&source.command)
+                )
+            })
+            .collect::<Vec<_>>();
+        let mappings = self
+            .mappings
+            .iter()
+            .map(|mapping| {
+                let rom_address = match mapping.rom_address {
+                    Some(rom_address) => rom_address.to_string(),
+                    None => "null".to_string(),
+                };
+                format!(
+                    "    {{\"asm_line\": {}, \"rom\": {}, \"source\": {}}}",
+                    mapping.asm_line, rom_address, mapping.source
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\n  \"sources\": {},\n  \"mappings\": {}\n}}\n",
+            json_array(&sources),
+            json_array(&mappings)
+        )
+    }
+}
+
+fn json_array(entries: &[String]) -> String {
+    if entries.is_empty() {
+        return "[]".to_string();
+    }
+    format!("[\n{}\n  ]", entries.join(",\n"))
+}
+
+/// ファイルのパスには`\`や`"`が入りうるのでエスケープする
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_map() {
+        let instrs = "// bootstrap\n@256\nD=A\n// constant 7\n@7\nD=A\n(L)\n@L\n0;JMP"
+            .lines()
+            .map(|line| line.parse().unwrap())
+            .collect::<Vec<AsmInstr>>();
+        let sources = vec![
+            SourceLocation {
+                vm_file: "dir\\Main.vm".to_string(),
+                line: 3,
+                command: "push constant 7".to_string(),
+            },
+            SourceLocation {
+                vm_file: "dir\\Main.vm".to_string(),
+                line: 4,
+                command: "label L".to_string(),
+            },
+        ];
+        let instr_sources = [
+            None,
+            None,
+            None,
+            Some(0),
+            Some(0),
+            Some(0),
+            Some(1),
+            None,
+            None,
+        ];
+        let source_map = SourceMap::new(sources, &instrs, &instr_sources);
+
+        assert_eq!(source_map.find_by_rom_address(3).unwrap().line, 3);
+        assert_eq!(source_map.find_by_rom_address(4), None);
+        let expect = r#"{
+  "sources": [
+    {"file": "dir\\Main.vm", "line": 3, "command": "push constant 7"},
+    {"file": "dir\\Main.vm", "line": 4, "command": "label L"}
+  ],
+  "mappings": [
+    {"asm_line": 4, "rom": null, "source": 0},
+    {"asm_line": 5, "rom": 2, "source": 0},
+    {"asm_line": 6, "rom": 3, "source": 0},
+    {"asm_line": 7, "rom": null, "source": 1}
+  ]
+}
+"#;
+        assert_eq!(source_map.to_json(), expect);
+        assert_eq!(
+            SourceMap::default().to_json(),
+            "{\n  \"sources\": [],\n  \"mappings\": []\n}\n"
+        );
+    }
+}
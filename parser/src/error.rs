@@ -0,0 +1,62 @@
+use std::{
+    fmt::{self, Display},
+    ops::Range,
+    path::PathBuf,
+};
+
+pub type ParseResult<T> = std::result::Result<T, ParseError>;
+
+/// VMコードの解析に失敗した位置と理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 解析中のvmファイルのパス。メモリ上のソースなどパスが無い場合はNone
+    pub path: Option<PathBuf>,
+    /// 1始まりの行番号
+    pub line: usize,
+    /// 1始まりの列範囲(終端は含まない)
+    pub column: Range<usize>,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(line: usize, column: Range<usize>, message: impl Into<String>) -> Self {
+        Self {
+            path: None,
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = match &self.path {
+            Some(path) => path.to_string_lossy().to_string(),
+            None => "<input>".to_string(),
+        };
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            path, self.line, self.column.start, self.message
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut error = ParseError::new(3, 6..14, "missing index");
+        assert_eq!(error.to_string(), "<input>:3:6: missing index");
+
+        error.path = Some(Path::new("Main.vm").to_path_buf());
+        assert_eq!(error.to_string(), "Main.vm:3:6: missing index");
+    }
+}
@@ -1,9 +1,20 @@
-use anyhow::Result;
+pub mod error;
+pub mod tokenizer;
+pub mod validator;
+
 use std::{
-    io::{BufRead, BufReader}, result::Result::Ok,
+    fmt,
+    io::{BufRead, BufReader},
+    ops::Range,
+    path::{Path, PathBuf},
+    result::Result::Ok,
 };
+use strum_macros::{AsRefStr, EnumString};
+
+pub use error::{ParseError, ParseResult};
+use tokenizer::{split_comment, tokenize, Token};
 
-const COMMENT_OUT_TOKEN: &str = "//";
+pub(crate) const COMMENT_OUT_TOKEN: &str = "//";
 const ARITHMETIC_COMMANDS: [&str; 9] = ["add", "sub", "neg", "eq", "gt", "lt", "and", "or", "not"];
 const PUSH_COMMAND: &str = "push";
 const POP_COMMAND: &str = "pop";
@@ -14,7 +25,7 @@ const FUNCTION_COMMAND: &str = "function";
 const RETURN_COMMAND: &str = "return";
 const CALL_COMMAND: &str = "call";
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum CommandType {
     Arithmetic,
     Push,
@@ -27,101 +38,348 @@ pub enum CommandType {
     Call,
 }
 
+impl CommandType {
+    /// 先頭トークンと完全一致するコマンドの種類を返す
+    pub fn from_keyword(keyword: &str) -> Option<Self> {
+        match keyword {
+            kw if ARITHMETIC_COMMANDS.contains(&kw) => Some(CommandType::Arithmetic),
+            PUSH_COMMAND => Some(CommandType::Push),
+            POP_COMMAND => Some(CommandType::Pop),
+            LABEL_COMMAND => Some(CommandType::Label),
+            GOTO_COMMAND => Some(CommandType::Goto),
+            IF_COMMAND => Some(CommandType::If),
+            FUNCTION_COMMAND => Some(CommandType::Function),
+            RETURN_COMMAND => Some(CommandType::Return),
+            CALL_COMMAND => Some(CommandType::Call),
+            _ => None,
+        }
+    }
+
+    /// コマンド名に続く引数の数
+    pub fn arity(&self) -> usize {
+        match self {
+            CommandType::Arithmetic | CommandType::Return => 0,
+            CommandType::Label | CommandType::Goto | CommandType::If => 1,
+            CommandType::Push | CommandType::Pop | CommandType::Function | CommandType::Call => 2,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, AsRefStr, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Segment {
+    Argument,
+    Local,
+    Static,
+    Constant,
+    This,
+    That,
+    Pointer,
+    Temp,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, AsRefStr, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+}
+
+/// VMコード1行分を解釈したコマンド
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Command {
+    Arithmetic(ArithOp),
+    Push { segment: Segment, index: u16 },
+    Pop { segment: Segment, index: u16 },
+    Label(String),
+    Goto(String),
+    If(String),
+    Function { name: String, n_vars: u16 },
+    Call { name: String, n_args: u16 },
+    Return,
+}
+
+impl Command {
+    pub fn command_type(&self) -> CommandType {
+        match self {
+            Command::Arithmetic(_) => CommandType::Arithmetic,
+            Command::Push { .. } => CommandType::Push,
+            Command::Pop { .. } => CommandType::Pop,
+            Command::Label(_) => CommandType::Label,
+            Command::Goto(_) => CommandType::Goto,
+            Command::If(_) => CommandType::If,
+            Command::Function { .. } => CommandType::Function,
+            Command::Call { .. } => CommandType::Call,
+            Command::Return => CommandType::Return,
+        }
+    }
+}
+
+/// VMコードの書式に戻す。引数の間は空白1つで区切る
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Arithmetic(op) => write!(f, "{}", op.as_ref()),
+            Command::Push { segment, index } => {
+                write!(f, "{} {} {}", PUSH_COMMAND, segment.as_ref(), index)
+            }
+            Command::Pop { segment, index } => {
+                write!(f, "{} {} {}", POP_COMMAND, segment.as_ref(), index)
+            }
+            Command::Label(label) => write!(f, "{} {}", LABEL_COMMAND, label),
+            Command::Goto(label) => write!(f, "{} {}", GOTO_COMMAND, label),
+            Command::If(label) => write!(f, "{} {}", IF_COMMAND, label),
+            Command::Function { name, n_vars } => {
+                write!(f, "{} {} {}", FUNCTION_COMMAND, name, n_vars)
+            }
+            Command::Call { name, n_args } => write!(f, "{} {} {}", CALL_COMMAND, name, n_args),
+            Command::Return => write!(f, "{}", RETURN_COMMAND),
+        }
+    }
+}
+
 pub struct Parser {
     vm_code: Box<dyn BufRead>,
-    current_command: Option<String>,
+    path: Option<PathBuf>,
+    line_number: usize,
+    current_command: Option<Command>,
+    current_comment: Option<String>,
+    // 現在のコマンドの各引数の列範囲。validateのエラー位置に使う
+    current_arg_columns: Vec<Range<usize>>,
 }
 
 impl Parser {
-    pub fn new<T:BufRead + 'static>(vm_file: T) -> Self {
+    pub fn new<T: BufRead + 'static>(vm_file: T) -> Self {
         Self {
             vm_code: Box::new(BufReader::new(vm_file)),
+            path: None,
+            line_number: 0,
             current_command: None,
+            current_comment: None,
+            current_arg_columns: Vec::new(),
         }
     }
 
-    pub fn has_more_lines(&mut self) -> Result<bool> {
-        Ok(self.vm_code.fill_buf()?.iter().next().is_some())
+    /// エラーメッセージに表示するvmファイルのパスを設定する
+    pub fn set_path(&mut self, path: &Path) {
+        self.path = Some(path.to_path_buf());
+    }
+
+    pub fn has_more_lines(&mut self) -> ParseResult<bool> {
+        match self.vm_code.fill_buf() {
+            Ok(buf) => Ok(!buf.is_empty()),
+            Err(e) => Err(self.error(self.line_number + 1, 1..1, e.to_string())),
+        }
     }
 
-    pub fn advance(&mut self) -> Result<()> {
-        // //で始まるコメント行と空白を無視して次の行を読み込む
+    pub fn advance(&mut self) -> ParseResult<()> {
+        // コメントと空白だけの行を無視して次の行を読み込む
+        self.current_command = None;
+        self.current_comment = None;
         while self.has_more_lines()? {
-            self.current_command = match self.vm_code.as_mut().lines().next().unwrap() {
-                Ok(line) if line.chars().all(char::is_whitespace) => None, //空白の場合は無視
-                Ok(line) if line.trim().starts_with(COMMENT_OUT_TOKEN) => None, //コメント行の場合は無視
-                Ok(line) => Some(line.trim().to_string()),
-                Err(_) => None,
-            };
-            if self.current_command.is_some() {
-                break;
+            self.line_number += 1;
+            let mut line = String::new();
+            if let Err(e) = self.vm_code.read_line(&mut line) {
+                return Err(self.error(self.line_number, 1..1, e.to_string()));
             }
+            let tokens = tokenize(&line);
+            if tokens.is_empty() {
+                continue; //空白とコメント行の場合は無視
+            }
+            self.current_command = Some(self.parse_command(&tokens)?);
+            self.current_arg_columns = tokens[1..]
+                .iter()
+                .map(|token| token.column.clone())
+                .collect();
+            self.current_comment = split_comment(&line)
+                .1
+                .filter(|comment| !comment.is_empty())
+                .map(str::to_string);
+            break;
         }
         Ok(())
     }
 
-    pub fn command_type(&self) -> Result<Option<CommandType>> {
-        let command = self.current_command.clone().expect("current command empty");
-        match command {
-            cmd if ARITHMETIC_COMMANDS
-                .iter()
-                .any(|arithmetic_command| cmd.starts_with(*arithmetic_command)) =>
-            {
-                Ok(Some(CommandType::Arithmetic))
+    /// 現在のコマンド。advanceで読み進めた結果、コマンドが残っていなければNone
+    pub fn command(&self) -> Option<&Command> {
+        self.current_command.as_ref()
+    }
+
+    /// 現在のコマンドがセグメントの範囲などの意味的な制約を満たしているか検査する
+    pub fn validate(&self) -> ParseResult<()> {
+        let Some(command) = self.current_command.as_ref() else {
+            return Ok(());
+        };
+        validator::validate(command).map_err(|violation| {
+            let column = self
+                .current_arg_columns
+                .get(violation.arg)
+                .cloned()
+                .unwrap_or(1..1);
+            self.error(self.line_number, column, violation.message)
+        })
+    }
+
+    /// 現在のコマンドの行末に書かれたコメント。//は含まない
+    pub fn comment(&self) -> Option<&str> {
+        self.current_comment.as_deref()
+    }
+
+    /// 現在のコマンドの1始まりの行番号
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    pub fn command_type(&self) -> ParseResult<Option<CommandType>> {
+        Ok(self.current_command.as_ref().map(Command::command_type))
+    }
+
+    pub fn arg1(&self) -> ParseResult<String> {
+        match self.current_command.as_ref() {
+            Some(Command::Arithmetic(op)) => Ok(op.as_ref().to_string()),
+            Some(Command::Push { segment, .. } | Command::Pop { segment, .. }) => {
+                Ok(segment.as_ref().to_string())
+            }
+            Some(Command::Label(label) | Command::Goto(label) | Command::If(label)) => {
+                Ok(label.to_string())
+            }
+            Some(Command::Function { name, .. } | Command::Call { name, .. }) => {
+                Ok(name.to_string())
             }
-            cmd if cmd.starts_with(PUSH_COMMAND) => Ok(Some(CommandType::Push)),
-            cmd if cmd.starts_with(POP_COMMAND) => Ok(Some(CommandType::Pop)),
-            cmd if cmd.starts_with(LABEL_COMMAND) => Ok(Some(CommandType::Label)),
-            cmd if cmd.starts_with(GOTO_COMMAND) => Ok(Some(CommandType::Goto)),
-            cmd if cmd.starts_with(IF_COMMAND) => Ok(Some(CommandType::If)),
-            cmd if cmd.starts_with(FUNCTION_COMMAND) => Ok(Some(CommandType::Function)),
-            cmd if cmd.starts_with(RETURN_COMMAND) => Ok(Some(CommandType::Return)),
-            cmd if cmd.starts_with(CALL_COMMAND) => Ok(Some(CommandType::Call)),
-            _ => Ok(None),
+            Some(Command::Return) => {
+                Err(self.error(self.line_number, 1..1, "return has no arguments"))
+            }
+            None => Err(self.error(self.line_number, 1..1, "no current command")),
         }
     }
 
-    pub fn arg1(&self) -> Result<String> {
-        let current_command = self
-            .current_command
-            .clone()
-            .expect("current command is empty");
-        let commands = current_command.split_whitespace();
-        match self.command_type()?.unwrap() {
-            CommandType::Arithmetic => Ok(commands.into_iter().nth(0).unwrap().to_string()),
-            CommandType::Push
-            | CommandType::Pop
-            | CommandType::Label
-            | CommandType::Goto
-            | CommandType::If
-            | CommandType::Function
-            | CommandType::Call => Ok(commands
-                .clone()
-                .into_iter()
-                .nth(1)
-                .expect(&format!("get nth 1 failed commands: {:?}", commands))
-                .to_string()),
-            _ => panic!("error parse command arg1: {:?}", commands),
+    pub fn arg2(&self) -> ParseResult<Option<u16>> {
+        match self.current_command.as_ref() {
+            Some(Command::Push { index, .. } | Command::Pop { index, .. }) => Ok(Some(*index)),
+            Some(Command::Function { n_vars, .. }) => Ok(Some(*n_vars)),
+            Some(Command::Call { n_args, .. }) => Ok(Some(*n_args)),
+            Some(_) => Ok(None),
+            None => Err(self.error(self.line_number, 1..1, "no current command")),
         }
     }
 
-    pub fn arg2(&self) -> Result<Option<u16>> {
-        let current_command = self
-            .current_command
-            .clone()
-            .expect("current command is empty");
-        let commands = current_command.split_whitespace();
-        match self.command_type()?.unwrap() {
-            CommandType::Push | CommandType::Pop | CommandType::Function | CommandType::Call => {
-                Ok(Some(commands.into_iter().nth(2).unwrap().parse()?))
-            }
-            _ => panic!("error parse command arg2: {:?}", commands),
+    fn parse_command(&self, tokens: &[Token]) -> ParseResult<Command> {
+        let keyword = &tokens[0];
+        let command_type = CommandType::from_keyword(keyword.text).ok_or_else(|| {
+            self.error(
+                self.line_number,
+                keyword.column.clone(),
+                format!("unknown command `{}`", keyword.text),
+            )
+        })?;
+
+        let args = &tokens[1..];
+        if let Some(extra) = args.get(command_type.arity()) {
+            return Err(self.error(
+                self.line_number,
+                extra.column.start..tokens.last().unwrap().column.end,
+                format!(
+                    "unexpected token `{}`: `{}` takes {} argument(s)",
+                    extra.text,
+                    keyword.text,
+                    command_type.arity()
+                ),
+            ));
+        }
+        let end_of_line = tokens.last().unwrap().column.end;
+        let arg = |n: usize, name: &str| -> ParseResult<&Token> {
+            args.get(n).ok_or_else(|| {
+                self.error(
+                    self.line_number,
+                    end_of_line..end_of_line,
+                    format!("missing {}", name),
+                )
+            })
+        };
+
+        let command = match command_type {
+            CommandType::Arithmetic => Command::Arithmetic(keyword.text.parse().unwrap()),
+            CommandType::Push => Command::Push {
+                segment: self.parse_segment(arg(0, "segment")?)?,
+                index: self.parse_number(arg(1, "index")?)?,
+            },
+            CommandType::Pop => Command::Pop {
+                segment: self.parse_segment(arg(0, "segment")?)?,
+                index: self.parse_number(arg(1, "index")?)?,
+            },
+            CommandType::Label => Command::Label(self.parse_symbol(arg(0, "label")?, "label")?),
+            CommandType::Goto => Command::Goto(self.parse_symbol(arg(0, "label")?, "label")?),
+            CommandType::If => Command::If(self.parse_symbol(arg(0, "label")?, "label")?),
+            CommandType::Function => Command::Function {
+                name: self.parse_symbol(arg(0, "function name")?, "function name")?,
+                n_vars: self.parse_number(arg(1, "number of local variables")?)?,
+            },
+            CommandType::Call => Command::Call {
+                name: self.parse_symbol(arg(0, "function name")?, "function name")?,
+                n_args: self.parse_number(arg(1, "number of arguments")?)?,
+            },
+            CommandType::Return => Command::Return,
+        };
+        Ok(command)
+    }
+
+    fn parse_segment(&self, token: &Token) -> ParseResult<Segment> {
+        token.text.parse().map_err(|_| {
+            self.error(
+                self.line_number,
+                token.column.clone(),
+                format!("unknown segment `{}`", token.text),
+            )
+        })
+    }
+
+    /// ラベルと関数名は英字、数字、`_.:`からなり、数字で始まらない。
+    /// `$`などを含めないことで、CodeWriterが生成するラベルと重ならないようにする
+    fn parse_symbol(&self, token: &Token, name: &str) -> ParseResult<String> {
+        let is_symbol_char = |c: char| c.is_ascii_alphanumeric() || "_.:".contains(c);
+        let starts_with_digit = token.text.starts_with(|c: char| c.is_ascii_digit());
+        if starts_with_digit || !token.text.chars().all(is_symbol_char) {
+            return Err(self.error(
+                self.line_number,
+                token.column.clone(),
+                format!("invalid {} `{}`", name, token.text),
+            ));
+        }
+        Ok(token.text.to_string())
+    }
+
+    fn parse_number(&self, token: &Token) -> ParseResult<u16> {
+        token.text.parse().map_err(|_| {
+            self.error(
+                self.line_number,
+                token.column.clone(),
+                format!(
+                    "expected a number between 0 and {}, found `{}`",
+                    u16::MAX,
+                    token.text
+                ),
+            )
+        })
+    }
+
+    fn error(&self, line: usize, column: Range<usize>, message: impl Into<String>) -> ParseError {
+        ParseError {
+            path: self.path.clone(),
+            ..ParseError::new(line, column, message)
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use anyhow::Result;
     use std::io::Cursor;
 
     use super::*;
@@ -132,7 +390,6 @@ mod tests {
         parser
             .vm_code
             .lines()
-            .into_iter()
             .for_each(|line| println!("{}", line.unwrap()));
     }
 
@@ -147,16 +404,16 @@ mod tests {
         let mut parser = Parser::new(Cursor::new(file_content.as_bytes()));
 
         parser.advance()?;
-        assert_eq!(parser.has_more_lines()?, true);
+        assert!(parser.has_more_lines()?);
 
         parser.advance()?;
-        assert_eq!(parser.has_more_lines()?, true);
+        assert!(parser.has_more_lines()?);
 
         parser.advance()?;
-        assert_eq!(parser.has_more_lines()?, true);
+        assert!(parser.has_more_lines()?);
 
         parser.advance()?;
-        assert_eq!(parser.has_more_lines()?, false);
+        assert!(!parser.has_more_lines()?);
 
         Ok(())
     }
@@ -241,4 +498,160 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_command() -> Result<()> {
+        let file_content = r#"
+        push constant 7
+        pop local 2
+        sub
+        label LOOP
+        goto LOOP
+        if-goto END
+        function Main.main 3
+        call Math.multiply 2
+        return
+       "#;
+        let mut parser = Parser::new(Cursor::new(file_content.as_bytes()));
+        let expects = vec![
+            Command::Push {
+                segment: Segment::Constant,
+                index: 7,
+            },
+            Command::Pop {
+                segment: Segment::Local,
+                index: 2,
+            },
+            Command::Arithmetic(ArithOp::Sub),
+            Command::Label("LOOP".to_string()),
+            Command::Goto("LOOP".to_string()),
+            Command::If("END".to_string()),
+            Command::Function {
+                name: "Main.main".to_string(),
+                n_vars: 3,
+            },
+            Command::Call {
+                name: "Math.multiply".to_string(),
+                n_args: 2,
+            },
+            Command::Return,
+        ];
+
+        for expect in expects {
+            parser.advance()?;
+            assert_eq!(parser.command(), Some(&expect));
+        }
+        parser.advance()?;
+        assert_eq!(parser.command(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_display() -> Result<()> {
+        let file_content = "push constant 7\npop local 2\nsub\nlabel LOOP\ngoto LOOP\nif-goto END\nfunction Main.main 3\ncall Math.multiply 2\nreturn\n";
+        let mut parser = Parser::new(Cursor::new(file_content.as_bytes()));
+        for line in file_content.lines() {
+            parser.advance()?;
+            assert_eq!(parser.command().unwrap().to_string(), line);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_error() -> Result<()> {
+        let file_content = "// comment\npush constant\n";
+        let mut parser = Parser::new(Cursor::new(file_content.as_bytes()));
+        parser.set_path(Path::new("Main.vm"));
+        let error = parser.advance().unwrap_err();
+        assert_eq!(error.path, Some(PathBuf::from("Main.vm")));
+        assert_eq!(error.line, 2);
+        assert_eq!(error.column, 14..14);
+        assert_eq!(error.to_string(), "Main.vm:2:14: missing index");
+
+        let mut parser = Parser::new(Cursor::new("  pop local x1\n".as_bytes()));
+        let error = parser.advance().unwrap_err();
+        assert_eq!((error.line, error.column), (1, 13..15));
+
+        let mut parser = Parser::new(Cursor::new("push heap 1\n".as_bytes()));
+        let error = parser.advance().unwrap_err();
+        assert_eq!(error.column, 6..10);
+        assert_eq!(error.message, "unknown segment `heap`");
+
+        let mut parser = Parser::new(Cursor::new("jump LOOP\n".as_bytes()));
+        let error = parser.advance().unwrap_err();
+        assert_eq!(error.column, 1..5);
+        assert_eq!(error.message, "unknown command `jump`");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exact_keyword() {
+        for (line, message, column) in [
+            ("address 3", "unknown command `address`", 1..8),
+            ("pushy constant 1", "unknown command `pushy`", 1..6),
+            ("nothing", "unknown command `nothing`", 1..8),
+            (
+                "add 1",
+                "unexpected token `1`: `add` takes 0 argument(s)",
+                5..6,
+            ),
+            (
+                "push constant 1 2 3",
+                "unexpected token `2`: `push` takes 2 argument(s)",
+                17..20,
+            ),
+            ("goto", "missing label", 5..5),
+            ("label cmp$2.true", "invalid label `cmp$2.true`", 7..17),
+            ("function 1f 0", "invalid function name `1f`", 10..12),
+            ("call VM$CALL 0", "invalid function name `VM$CALL`", 6..13),
+        ] {
+            let mut parser = Parser::new(Cursor::new(line.as_bytes()));
+            let error = parser.advance().unwrap_err();
+            assert_eq!(error.message, message);
+            assert_eq!(error.column, column);
+        }
+    }
+
+    #[test]
+    fn test_trailing_comment() -> Result<()> {
+        let mut parser = Parser::new(Cursor::new("label LOOP // loop start\n".as_bytes()));
+        parser.advance()?;
+        assert_eq!(parser.command(), Some(&Command::Label("LOOP".to_string())));
+        assert_eq!(parser.comment(), Some("loop start"));
+
+        let mut parser = Parser::new(Cursor::new("add\n".as_bytes()));
+        parser.advance()?;
+        assert_eq!(parser.comment(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let mut parser = Parser::new(Cursor::new(
+            "push temp 7\npop constant 3\npush pointer 2\npush local 65535\n".as_bytes(),
+        ));
+        parser.set_path(Path::new("Main.vm"));
+        parser.advance()?;
+        parser.validate()?;
+
+        parser.advance()?;
+        assert_eq!(
+            parser.validate().unwrap_err().to_string(),
+            "Main.vm:2:5: cannot pop to the constant segment"
+        );
+
+        parser.advance()?;
+        let error = parser.validate().unwrap_err();
+        assert_eq!((error.line, error.column), (3, 14..15));
+
+        // A命令に収まらないインデックスはアドレスの計算で桁あふれする前に弾く
+        parser.advance()?;
+        assert_eq!(
+            parser.validate().unwrap_err().to_string(),
+            "Main.vm:4:12: local index must be between 0 and 32767, found 65535"
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,96 @@
+use std::ops::Range;
+
+use crate::COMMENT_OUT_TOKEN;
+
+/// 1行から切り出した空白区切りのトークン
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    /// 1始まりの列範囲(終端は含まない)
+    pub column: Range<usize>,
+}
+
+/// 行をコードとコメントに分ける。コメントは//の後ろを前後の空白を除いて返す
+pub fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match line.find(COMMENT_OUT_TOKEN) {
+        Some(comment_start) => (
+            &line[..comment_start],
+            Some(line[comment_start + COMMENT_OUT_TOKEN.len()..].trim()),
+        ),
+        None => (line, None),
+    }
+}
+
+/// 行を空白で区切ってトークンに分割する。//以降はコメントとして扱いトークンに含めない
+pub fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let (line, _) = split_comment(line);
+    let mut tokens = Vec::new();
+    let mut start: Option<(usize, usize)> = None; // (バイト位置, 列)
+    let mut end_column = 1;
+    for (column, (byte_index, c)) in (1..).zip(line.char_indices()) {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((byte_index, column)),
+            (true, Some((start_byte, start_column))) => {
+                tokens.push(Token {
+                    text: &line[start_byte..byte_index],
+                    column: start_column..column,
+                });
+                start = None;
+            }
+            _ => (),
+        }
+        end_column = column + 1;
+    }
+    if let Some((start_byte, start_column)) = start {
+        tokens.push(Token {
+            text: &line[start_byte..],
+            column: start_column..end_column,
+        });
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_comment() {
+        assert_eq!(
+            split_comment("push local 0 // load x\n"),
+            ("push local 0 ", Some("load x"))
+        );
+        assert_eq!(split_comment("add"), ("add", None));
+        assert_eq!(split_comment("label END//"), ("label END", Some("")));
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("  push\tconstant 7 "),
+            vec![
+                Token {
+                    text: "push",
+                    column: 3..7
+                },
+                Token {
+                    text: "constant",
+                    column: 8..16
+                },
+                Token {
+                    text: "7",
+                    column: 17..18
+                },
+            ]
+        );
+        assert_eq!(tokenize("   "), vec![]);
+        assert_eq!(tokenize("// comment"), vec![]);
+        assert_eq!(
+            tokenize("add// comment"),
+            vec![Token {
+                text: "add",
+                column: 1..4
+            }]
+        );
+    }
+}
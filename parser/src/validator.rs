@@ -0,0 +1,148 @@
+use crate::{Command, Segment};
+
+const MAX_TEMP_INDEX: u16 = 7; // TEMPセグメントはRAM[5~12]の8ワード
+const MAX_POINTER_INDEX: u16 = 1; // 0:THIS 1:THAT
+const MAX_CONSTANT: u16 = 32767; // Hackの15ビット定数
+const MAX_STATIC_INDEX: u16 = 239; // static変数はRAM[16~255]の240ワード
+const MAX_A_VALUE: u16 = 32767; // local、argument、this、thatのインデックスと関数の変数、引数の数はA命令で読み込む
+
+/// 構文としては正しいが意味的に翻訳できないコマンドの指摘
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Violation {
+    /// 問題のある引数の位置(0始まり)
+    pub arg: usize,
+    pub message: String,
+}
+
+/// セグメント名とインデックスの組み合わせ、関数のローカル変数と引数の数が翻訳可能か検査する
+pub fn validate(command: &Command) -> Result<(), Violation> {
+    match command {
+        Command::Pop {
+            segment: Segment::Constant,
+            ..
+        } => Err(Violation {
+            arg: 0,
+            message: "cannot pop to the constant segment".to_string(),
+        }),
+        Command::Push { segment, index } | Command::Pop { segment, index } => {
+            let max_index = match segment {
+                Segment::Temp => MAX_TEMP_INDEX,
+                Segment::Pointer => MAX_POINTER_INDEX,
+                Segment::Constant => MAX_CONSTANT,
+                Segment::Static => MAX_STATIC_INDEX,
+                Segment::Local | Segment::Argument | Segment::This | Segment::That => MAX_A_VALUE,
+            };
+            if *index > max_index {
+                return Err(Violation {
+                    arg: 1,
+                    message: format!(
+                        "{} index must be between 0 and {}, found {}",
+                        segment.as_ref(),
+                        max_index,
+                        index
+                    ),
+                });
+            }
+            Ok(())
+        }
+        Command::Function { n_vars, .. } => validate_count("nVars", *n_vars),
+        Command::Call { n_args, .. } => validate_count("nArgs", *n_args),
+        _ => Ok(()),
+    }
+}
+
+fn validate_count(name: &str, count: u16) -> Result<(), Violation> {
+    if count > MAX_A_VALUE {
+        return Err(Violation {
+            arg: 1,
+            message: format!(
+                "{} must be between 0 and {}, found {}",
+                name, MAX_A_VALUE, count
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let push = |segment, index| Command::Push { segment, index };
+        let pop = |segment, index| Command::Pop { segment, index };
+
+        for command in [
+            push(Segment::Temp, 7),
+            pop(Segment::Pointer, 1),
+            push(Segment::Constant, 32767),
+            push(Segment::Local, 1000),
+            pop(Segment::Static, 239),
+            Command::Call {
+                name: "Main.f".to_string(),
+                n_args: 32767,
+            },
+            Command::Return,
+        ] {
+            assert_eq!(validate(&command), Ok(()));
+        }
+
+        for (command, arg, message) in [
+            (
+                pop(Segment::Constant, 3),
+                0,
+                "cannot pop to the constant segment",
+            ),
+            (
+                push(Segment::Pointer, 2),
+                1,
+                "pointer index must be between 0 and 1, found 2",
+            ),
+            (
+                push(Segment::Temp, 9),
+                1,
+                "temp index must be between 0 and 7, found 9",
+            ),
+            (
+                push(Segment::Constant, 40000),
+                1,
+                "constant index must be between 0 and 32767, found 40000",
+            ),
+            (
+                push(Segment::Local, 65535),
+                1,
+                "local index must be between 0 and 32767, found 65535",
+            ),
+            (
+                pop(Segment::Static, 240),
+                1,
+                "static index must be between 0 and 239, found 240",
+            ),
+            (
+                Command::Function {
+                    name: "Main.f".to_string(),
+                    n_vars: 40000,
+                },
+                1,
+                "nVars must be between 0 and 32767, found 40000",
+            ),
+            (
+                Command::Call {
+                    name: "Main.f".to_string(),
+                    n_args: 40000,
+                },
+                1,
+                "nArgs must be between 0 and 32767, found 40000",
+            ),
+        ] {
+            assert_eq!(
+                validate(&command),
+                Err(Violation {
+                    arg,
+                    message: message.to_string()
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,646 @@
+use anyhow::{bail, Context, Result};
+use code_writer::{CodeWriter, SourceLocation};
+use parser::{Command, Parser};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Cursor, Write},
+    path::{Path, PathBuf},
+};
+use strum_macros::{AsRefStr, EnumString};
+
+pub use code_writer::{
+    Bootstrap, CodeWriterOptions, Object, OptimizeReport, OutputStyle, SegmentBases, SourceMap,
+};
+pub use parser::ParseError;
+
+pub const VM_FILE_EXTENSION: &str = "vm";
+pub const ASSEMBLY_FILE_EXTENSION: &str = "asm";
+pub const HACK_FILE_EXTENSION: &str = "hack";
+pub const OBJECT_FILE_EXTENSION: &str = "vmo";
+const SYS_INIT_FUNCTION: &str = "Sys.init";
+const SYS_VM_FILE_NAME: &str = "Sys.vm";
+
+/// 翻訳する1つのvmファイル。pathはstatic変数の接頭辞とエラーメッセージに使い、ファイルが実在する必要はない
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Input {
+    pub path: PathBuf,
+    pub source: String,
+}
+
+impl Input {
+    pub fn new(path: impl Into<PathBuf>, source: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let source =
+            fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        Ok(Self::new(path, source))
+    }
+}
+
+/// 翻訳結果として書き出す形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, AsRefStr, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Emit {
+    /// Hackアセンブリ(.asm)
+    #[default]
+    Asm,
+    /// アセンブルした機械語(.hack)
+    Hack,
+}
+
+impl Emit {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Emit::Asm => ASSEMBLY_FILE_EXTENSION,
+            Emit::Hack => HACK_FILE_EXTENSION,
+        }
+    }
+
+    /// 翻訳したアセンブリをこの形式の内容にする
+    pub fn render(self, assembly: String) -> Result<String> {
+        Ok(match self {
+            Emit::Asm => assembly,
+            Emit::Hack => assembler::assemble(&assembly)?.to_hack(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Options {
+    pub code_writer: CodeWriterOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Translation {
+    /// 生成したアセンブリ。diagnosticsが1件でもある場合は生成しない
+    pub assembly: Option<String>,
+    /// 全ての入力から見つかった構文、意味のエラー
+    pub diagnostics: Vec<ParseError>,
+    /// optimizeオプションが有効な場合に最適化で減った命令数
+    pub optimization: Option<OptimizeReport>,
+    /// アセンブリの各行と生成元のVMコマンドの対応。assemblyと同じくエラーがある場合は生成しない
+    pub source_map: Option<SourceMap>,
+}
+
+/// vmファイルをまとめて1つのアセンブリに翻訳する。
+/// 入力の誤りはdiagnosticsに集めて返し、Errは翻訳自体を続けられない場合だけ返す
+pub fn translate(inputs: &[Input], options: &Options) -> Result<Translation> {
+    let mut diagnostics = Vec::new();
+    // アセンブリを書き始める前に全てのvmファイルを解析、検査しておく
+    let parsed_inputs = inputs
+        .iter()
+        .map(|input| (input, parse_input(input, &mut diagnostics)))
+        .collect::<Vec<_>>();
+    if !diagnostics.is_empty() {
+        return Ok(Translation {
+            assembly: None,
+            diagnostics,
+            optimization: None,
+            source_map: None,
+        });
+    }
+
+    // Sys.initが無いchapter7形式のvmファイルではブートストラップコードを出力しない
+    let has_sys_init = parsed_inputs.iter().any(|(_, lines)| {
+        lines.iter().any(|line| {
+            matches!(&line.command, Command::Function { name, .. } if name == SYS_INIT_FUNCTION)
+        })
+    });
+    let code_writer_options = CodeWriterOptions {
+        bootstrap: options.code_writer.bootstrap.resolve(has_sys_init),
+        ..options.code_writer.clone()
+    };
+
+    let module_name = inputs
+        .first()
+        .and_then(|input| input.path.file_stem())
+        .unwrap_or_default()
+        .to_string_lossy();
+    let mut code_writer = CodeWriter::with_options(Vec::new(), &module_name, code_writer_options)?;
+    for (input, lines) in parsed_inputs {
+        write_input(&mut code_writer, input, &lines)?;
+    }
+
+    let (assembly, report) = code_writer.close_with_report()?;
+    Ok(Translation {
+        assembly: Some(String::from_utf8(assembly)?),
+        diagnostics,
+        optimization: report.optimization,
+        source_map: Some(report.source_map),
+    })
+}
+
+/// 1つのvmファイルを翻訳した結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compilation {
+    /// 翻訳したオブジェクト。diagnosticsが1件でもある場合は生成しない
+    pub object: Option<Object>,
+    pub diagnostics: Vec<ParseError>,
+}
+
+/// vmファイルを1つずつ、リンク前のオブジェクトに翻訳する。
+/// ブートストラップと共通処理はリンクで加えるので、optionsのbootstrapは使わない
+pub fn compile(input: &Input, options: &Options) -> Result<Compilation> {
+    let mut diagnostics = Vec::new();
+    let lines = parse_input(input, &mut diagnostics);
+    if !diagnostics.is_empty() {
+        return Ok(Compilation {
+            object: None,
+            diagnostics,
+        });
+    }
+
+    let code_writer_options = CodeWriterOptions {
+        bootstrap: Bootstrap::Off,
+        ..options.code_writer.clone()
+    };
+    let mut code_writer = CodeWriter::with_options(io::sink(), "", code_writer_options)?;
+    write_input(&mut code_writer, input, &lines)?;
+    Ok(Compilation {
+        object: Some(code_writer.into_object()?),
+        diagnostics,
+    })
+}
+
+/// リンクで見つかった関数の過不足
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// callされているが、どのオブジェクトでも定義されていない関数
+    UndefinedFunction { name: String, caller: String },
+    /// 2つのオブジェクトで定義されている関数
+    DuplicateFunction {
+        name: String,
+        first: String,
+        second: String,
+    },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::UndefinedFunction { name, caller } => {
+                write!(f, "undefined function `{}` called from {}", name, caller)
+            }
+            LinkError::DuplicateFunction {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "duplicate function `{}` defined in {} and {}",
+                name, first, second
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Linking {
+    /// リンクしたプログラムのアセンブリ。errorsが1件でもある場合は生成しない
+    pub assembly: Option<String>,
+    pub errors: Vec<LinkError>,
+    /// optimizeオプションが有効な場合に最適化で減った命令数
+    pub optimization: Option<OptimizeReport>,
+}
+
+/// オブジェクトを並べた順に1つのプログラムにまとめ、ブートストラップと共通処理を加える。
+/// 関数の過不足はerrorsに集めて返し、Errはリンク自体を続けられない場合だけ返す
+pub fn link(objects: &[Object], options: &Options) -> Result<Linking> {
+    let mut errors = Vec::new();
+    let mut definitions: HashMap<&str, &str> = HashMap::new();
+    for object in objects {
+        for name in &object.exports {
+            if let Some(first) = definitions.insert(name, &object.module) {
+                errors.push(LinkError::DuplicateFunction {
+                    name: name.clone(),
+                    first: first.to_string(),
+                    second: object.module.clone(),
+                });
+            }
+        }
+    }
+
+    let has_sys_init = definitions.contains_key(SYS_INIT_FUNCTION);
+    let code_writer_options = CodeWriterOptions {
+        bootstrap: options.code_writer.bootstrap.resolve(has_sys_init),
+        ..options.code_writer.clone()
+    };
+    // ブートストラップコードもSys.initを呼び出す
+    let bootstrap_imports = (code_writer_options.bootstrap == Bootstrap::On)
+        .then(|| ("bootstrap", SYS_INIT_FUNCTION.to_string()));
+    let imports = objects
+        .iter()
+        .flat_map(|object| {
+            object
+                .imports
+                .iter()
+                .map(|name| (object.module.as_str(), name.clone()))
+        })
+        .chain(bootstrap_imports);
+    for (caller, name) in imports {
+        if !definitions.contains_key(name.as_str()) {
+            errors.push(LinkError::UndefinedFunction {
+                name,
+                caller: caller.to_string(),
+            });
+        }
+    }
+    if !errors.is_empty() {
+        return Ok(Linking {
+            assembly: None,
+            errors,
+            optimization: None,
+        });
+    }
+
+    let module_name = objects
+        .first()
+        .map(|object| object.module.as_str())
+        .unwrap_or_default();
+    let mut code_writer = CodeWriter::with_options(Vec::new(), module_name, code_writer_options)?;
+    for object in objects {
+        code_writer.write_object(object)?;
+    }
+    let (assembly, report) = code_writer.close_with_report()?;
+    Ok(Linking {
+        assembly: Some(String::from_utf8(assembly)?),
+        errors,
+        optimization: report.optimization,
+    })
+}
+
+/// 指定されたパスからリンクするオブジェクトファイルを集める。
+/// ディレクトリの場合は直下のオブジェクトファイルを名前順に並べる
+pub fn collect_object_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return match path.extension() {
+            Some(extension) if extension == OBJECT_FILE_EXTENSION => Ok(vec![path.to_path_buf()]),
+            _ => bail!("un supported file: {:?}", path),
+        };
+    }
+    let mut object_files = path
+        .read_dir()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|extension| extension == OBJECT_FILE_EXTENSION)
+        })
+        .collect::<Vec<_>>();
+    if object_files.is_empty() {
+        bail!("no .{} files found: {:?}", OBJECT_FILE_EXTENSION, path);
+    }
+    object_files.sort();
+    Ok(object_files)
+}
+
+/// 指定されたパスから翻訳するvmファイルを集める。
+/// ディレクトリの場合は直下のvmファイルを名前順に並べ、sys_firstならSys.vmを先頭にする
+pub fn collect_vm_files(path: &Path, sys_first: bool) -> Result<Vec<PathBuf>> {
+    let mut vm_files: Vec<PathBuf> = Vec::new();
+    // 引数で指定されたのがディレクトリであればvmファイルのパスを読み取る
+    if path.is_dir() {
+        for entry in path.read_dir()?.flatten() {
+            //　現在のディレクトリのファイルまで見る。再帰的にディレクトリに潜っていくことはしない。
+            if entry.path().is_file() {
+                match entry.path().extension() {
+                    Some(file_extension) if file_extension == VM_FILE_EXTENSION => {
+                        vm_files.push(entry.path());
+                    }
+                    _ => (),
+                }
+            }
+        }
+    } else {
+        match path.extension() {
+            Some(extension) if extension == VM_FILE_EXTENSION => vm_files.push(path.to_path_buf()),
+            _ => bail!("un supported file: {:?}", path),
+        }
+    }
+
+    if vm_files.is_empty() {
+        bail!("no .vm files found: {:?}", path);
+    }
+    sort_vm_files(&mut vm_files, sys_first);
+    Ok(vm_files)
+}
+
+/// ディレクトリの場合は`<dir>/<dir>.asm`、ファイルの場合は拡張子をasmに変えたパスに出力する
+pub fn default_output_path(path: &Path) -> Result<PathBuf> {
+    if !path.is_dir() {
+        return Ok(path.with_extension(ASSEMBLY_FILE_EXTENSION));
+    }
+    // "./"のようにファイル名を持たないパスでもディレクトリ名が取れるよう絶対パスにする
+    let dir_name = path
+        .canonicalize()?
+        .file_name()
+        .with_context(|| format!("cannot determine directory name: {:?}", path))?
+        .to_string_lossy()
+        .to_string();
+    Ok(path.join(format!("{}.{}", dir_name, ASSEMBLY_FILE_EXTENSION)))
+}
+
+/// read_dirの順序はファイルシステムに依存するので、同じ入力から常に同じ出力になるよう名前順に並べる
+fn sort_vm_files(vm_files: &mut [PathBuf], sys_first: bool) {
+    vm_files.sort();
+    if sys_first {
+        // 安定ソートなのでSys.vm以外は名前順のまま残る
+        vm_files.sort_by_key(|vm_file| {
+            vm_file
+                .file_name()
+                .is_none_or(|file_name| file_name != SYS_VM_FILE_NAME)
+        });
+    }
+}
+
+struct VmLine {
+    command: Command,
+    line: usize,
+    comment: Option<String>,
+}
+
+/// 解析済みの1つの入力をコメントと生成元の位置とともに書き込む
+fn write_input<W: Write>(
+    code_writer: &mut CodeWriter<W>,
+    input: &Input,
+    lines: &[VmLine],
+) -> Result<()> {
+    code_writer.set_filename(&input.path)?;
+    for line in lines {
+        code_writer.set_source_location(SourceLocation {
+            vm_file: input.path.to_string_lossy().to_string(),
+            line: line.line,
+            command: line.command.to_string(),
+        });
+        if let Some(comment) = &line.comment {
+            code_writer.write_comment(comment)?;
+        }
+        code_writer.write_command(&line.command)?;
+    }
+    Ok(())
+}
+
+/// 入力を最後まで解析し、エラーのある行はdiagnosticsに積んで読み飛ばす
+fn parse_input(input: &Input, diagnostics: &mut Vec<ParseError>) -> Vec<VmLine> {
+    let mut parser = Parser::new(Cursor::new(input.source.clone().into_bytes()));
+    parser.set_path(&input.path);
+    let mut lines = Vec::new();
+    loop {
+        match parser.has_more_lines() {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => {
+                diagnostics.push(e);
+                break;
+            }
+        }
+        if let Err(e) = parser.advance().and_then(|_| parser.validate()) {
+            diagnostics.push(e);
+            continue;
+        }
+        if let Some(command) = parser.command() {
+            lines.push(VmLine {
+                command: command.clone(),
+                line: parser.line_number(),
+                comment: parser.comment().map(str::to_string),
+            });
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_test() -> Result<()> {
+        let inputs = [Input::new(
+            "Main.vm",
+            "push constant 7\npush constant 8\nadd // 7 + 8\npop static 0\n",
+        )];
+        let translation = translate(&inputs, &Options::default())?;
+        assert!(translation.diagnostics.is_empty());
+        let assembly = translation.assembly.unwrap();
+        // Sys.initが無いのでブートストラップコードは出力されない
+        assert!(assembly.starts_with("// constant 7\n"));
+        assert!(assembly.contains("// 7 + 8\n"));
+        assert!(assembly.contains("@Main.0\n"));
+        assert_eq!(translation.optimization, None);
+
+        let options = Options {
+            code_writer: CodeWriterOptions {
+                optimize: true,
+                ..Default::default()
+            },
+        };
+        let translation = translate(&inputs, &options)?;
+        let report = translation.optimization.unwrap();
+        assert!(report.saved() > 0);
+        assert!(translation.assembly.unwrap().len() < assembly.len());
+        Ok(())
+    }
+
+    #[test]
+    fn source_map_test() -> Result<()> {
+        let inputs = [Input::new(
+            "Main.vm",
+            "// 7 + 8\npush constant 7\npush constant 8\nadd // 7 + 8\npop static 0\n",
+        )];
+        for optimize in [false, true] {
+            let options = Options {
+                code_writer: CodeWriterOptions {
+                    optimize,
+                    ..Default::default()
+                },
+            };
+            let translation = translate(&inputs, &options)?;
+            let assembly = translation.assembly.unwrap();
+            let source_map = translation.source_map.unwrap();
+            let source_of = |asm_line: &str| {
+                let line_number = assembly.lines().position(|line| line == asm_line).unwrap() + 1;
+                source_map
+                    .mappings
+                    .iter()
+                    .find(|mapping| mapping.asm_line == line_number)
+                    .map(|mapping| &source_map.sources[mapping.source])
+            };
+
+            let add = source_of("// add").unwrap();
+            assert_eq!((add.line, add.command.as_str()), (4, "add"));
+            let pop = source_of("@Main.0").unwrap();
+            assert_eq!((pop.line, pop.command.as_str()), (5, "pop static 0"));
+            assert_eq!(pop.vm_file, "Main.vm");
+            // 終了の無限ループはVMコマンドに由来しない
+            assert_eq!(source_of("(END)"), None);
+            assert_eq!(source_map.find_by_rom_address(0).unwrap().line, 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn emit_hack_test() -> Result<()> {
+        let dir = Path::new("test_vm_files/8/FunctionCalls/FibonacciElement");
+        let inputs = collect_vm_files(dir, false)?
+            .iter()
+            .map(|vm_file| Input::read(vm_file))
+            .collect::<Result<Vec<_>>>()?;
+        let assembly = translate(&inputs, &Options::default())?.assembly.unwrap();
+        let instruction_count = assembly
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with("//") && !line.starts_with('('))
+            .count();
+
+        let hack = Emit::Hack.render(assembly.clone())?;
+        assert_eq!(hack.lines().count(), instruction_count);
+        assert!(hack
+            .lines()
+            .all(|line| line.len() == 16 && line.chars().all(|c| c == '0' || c == '1')));
+        assert_eq!(Emit::Asm.render(assembly.clone())?, assembly);
+        assert_eq!("hack".parse::<Emit>()?, Emit::Hack);
+        Ok(())
+    }
+
+    #[test]
+    fn compile_and_link_test() -> Result<()> {
+        let dir = Path::new("test_vm_files/8/FunctionCalls/FibonacciElement");
+        let objects = collect_vm_files(dir, false)?
+            .iter()
+            .map(|vm_file| {
+                let object = compile(&Input::read(vm_file)?, &Options::default())?
+                    .object
+                    .unwrap();
+                // ファイルに書き出して読み戻しても同じオブジェクトになる
+                assert_eq!(object.to_string().parse::<Object>()?, object);
+                Ok(object)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(objects[0].module, "Main");
+        assert_eq!(objects[0].exports, ["Main.fibonacci"]);
+        assert_eq!(objects[1].imports, ["Main.fibonacci"]);
+
+        let linking = link(&objects, &Options::default())?;
+        assert!(linking.errors.is_empty());
+        let assembly = linking.assembly.unwrap();
+        // Sys.initがあるのでブートストラップコードから始まる
+        assert!(assembly.starts_with("// bootstrap\n"));
+        let program = assembler::assemble(&assembly)?;
+        let labels = program.symbols.labels();
+        assert!(labels.iter().any(|(name, _)| name == "Main.fibonacci"));
+        assert!(labels.iter().any(|(name, _)| name == "Sys$ret$0"));
+        Ok(())
+    }
+
+    #[test]
+    fn link_reports_errors() -> Result<()> {
+        let compile_source = |path: &str, source: &str| -> Result<Object> {
+            Ok(compile(&Input::new(path, source), &Options::default())?
+                .object
+                .unwrap())
+        };
+        let objects = [
+            compile_source("Main.vm", "function Main.main 0\ncall Math.max 0\nreturn\n")?,
+            compile_source(
+                "Other.vm",
+                "function Main.main 0\npush constant 0\nreturn\n",
+            )?,
+        ];
+        let options = Options {
+            code_writer: CodeWriterOptions {
+                bootstrap: Bootstrap::On,
+                ..Default::default()
+            },
+        };
+        let linking = link(&objects, &options)?;
+        assert_eq!(linking.assembly, None);
+        assert_eq!(
+            linking
+                .errors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "duplicate function `Main.main` defined in Main and Other",
+                "undefined function `Math.max` called from Main",
+                "undefined function `Sys.init` called from bootstrap",
+            ]
+        );
+
+        let compilation = compile(&Input::new("Main.vm", "pushy\n"), &Options::default())?;
+        assert_eq!(compilation.object, None);
+        assert_eq!(compilation.diagnostics.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn translate_collects_diagnostics() -> Result<()> {
+        let inputs = [
+            Input::new("Main.vm", "push constant\nadd\npop constant 1\n"),
+            Input::new("Sys.vm", "function Sys.init 0\npushy local 0\n"),
+        ];
+        let translation = translate(&inputs, &Options::default())?;
+        assert_eq!(translation.assembly, None);
+        assert_eq!(
+            translation
+                .diagnostics
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "Main.vm:1:14: missing index",
+                "Main.vm:3:5: cannot pop to the constant segment",
+                "Sys.vm:2:1: unknown command `pushy`",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sort_vm_files_test() {
+        let mut vm_files: Vec<PathBuf> = ["Sys.vm", "Main.vm", "Ball.vm", "Bat.vm"]
+            .map(PathBuf::from)
+            .to_vec();
+        sort_vm_files(&mut vm_files, false);
+        assert_eq!(
+            vm_files,
+            ["Ball.vm", "Bat.vm", "Main.vm", "Sys.vm"].map(PathBuf::from)
+        );
+
+        sort_vm_files(&mut vm_files, true);
+        assert_eq!(
+            vm_files,
+            ["Sys.vm", "Ball.vm", "Bat.vm", "Main.vm"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn default_output_path_test() -> Result<()> {
+        assert_eq!(
+            default_output_path(Path::new("Main.vm"))?,
+            PathBuf::from("Main.asm")
+        );
+        assert_eq!(
+            default_output_path(Path::new("StackTest/StackTest.vm"))?,
+            PathBuf::from("StackTest/StackTest.asm")
+        );
+
+        let dir = "test_vm_files/8/FunctionCalls/FibonacciElement";
+        assert_eq!(
+            default_output_path(Path::new(dir))?,
+            Path::new(dir).join("FibonacciElement.asm")
+        );
+        assert_eq!(
+            default_output_path(&Path::new(dir).join("."))?,
+            Path::new(dir).join(".").join("FibonacciElement.asm")
+        );
+        Ok(())
+    }
+}
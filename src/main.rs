@@ -1,153 +1,540 @@
-use anyhow::Result;
-use std::{fs::File, io::BufReader, path::{Path, PathBuf}};
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use vmtranslator::{
+    collect_object_files, collect_vm_files, compile, default_output_path, link, translate,
+    CodeWriterOptions, Emit, Input, Object, OptimizeReport, Options, OBJECT_FILE_EXTENSION,
+};
 
-const ASSEMBLY_FILE_EXTENSION: &str = "asm";
+const STDOUT_OUTPUT: &str = "-";
+const MAX_ADDRESS: u16 = 32767;
 
 fn main() -> Result<()> {
-    if let Err(e) = vm_translator(&parse_arg(std::env::args().collect())?) {
+    if let Err(e) = parse_arg(std::env::args().collect()).and_then(|args| vm_translator(&args)) {
         eprintln!("{}", e);
         std::process::exit(1);
     }
     Ok(())
 }
 
-fn parse_arg(args: Vec<String>) -> Result<String> {
-    let current_dir = "./".to_string();
-    match args.get(1) {
-        Some(arg) if arg.is_empty() => Ok(current_dir),
-        Some(arg) => Ok(arg.to_string()),
-        _ => Ok(current_dir),
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum Mode {
+    /// vmファイルをまとめて1つのプログラムに翻訳する
+    #[default]
+    Translate,
+    /// vmファイルを1つずつオブジェクトに翻訳する
+    Compile,
+    /// オブジェクトをまとめて1つのプログラムにする
+    Link,
+}
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    mode: Mode,
+    // 入力のパス。リンクする場合だけ複数指定できる
+    paths: Vec<String>,
+    // ディレクトリ内のvmファイルを名前順に並べたうえでSys.vmを先頭にする
+    sys_first: bool,
+    // 出力先のasmファイルのパス。"-"の場合は標準出力に書き込む
+    output: Option<String>,
+    emit: Emit,
+    // ラベルのROMアドレスと変数のRAMアドレスの一覧を書き込むJSONファイルのパス
+    symbols: Option<String>,
+    // アセンブリの各行と生成元のVMコマンドの対応を書き込むJSONファイルのパス
+    source_map: Option<String>,
+    options: CodeWriterOptions,
 }
 
-fn vm_translator(path_str: &str) -> Result<()> {
-    let path = Path::new(path_str);
-    let is_dir = path.is_dir();
-    let mut vm_files: Vec<PathBuf> = Vec::new();
-    // 引数で指定されたのがディレクトリであればvmファイルのパスを読み取る
-    if is_dir {
-        for entry in path.read_dir()? {
-            if let Ok(entry) = entry {
-                //　現在のディレクトリのファイルまで見る。再帰的にディレクトリに潜っていくことはしない。
-                if entry.path().is_file() {
-                    match entry.path().extension() {
-                        Some(file_extension) if file_extension == "vm" => {
-                            vm_files.push(entry.path());
-                        }
-                        _ => (),
-                    }
-                }
+fn parse_arg(args: Vec<String>) -> Result<Args> {
+    let current_dir = "./".to_string();
+    let mut mode = Mode::default();
+    let mut paths: Vec<String> = Vec::new();
+    let mut sys_first = false;
+    let mut output: Option<String> = None;
+    let mut emit = Emit::default();
+    let mut symbols: Option<String> = None;
+    let mut source_map: Option<String> = None;
+    let mut options = CodeWriterOptions::default();
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-o" || arg == "--output" {
+            output = Some(
+                args.next()
+                    .with_context(|| format!("missing value for {}", arg))?,
+            );
+            continue;
+        }
+        if !arg.starts_with("--") {
+            paths.push(arg);
+            continue;
+        }
+        if arg == "--compile" || arg == "--link" {
+            if mode != Mode::Translate {
+                bail!("--compile and --link cannot be used together");
             }
+            mode = if arg == "--compile" {
+                Mode::Compile
+            } else {
+                Mode::Link
+            };
+            continue;
+        }
+        if arg == "--sys-first" {
+            sys_first = true;
+            continue;
+        }
+        if arg == "--raw-labels" {
+            options.raw_labels_outside_function = true;
+            continue;
+        }
+        if arg == "--opt" {
+            options.optimize = true;
+            continue;
+        }
+        if arg == "--shared-calls" {
+            options.shared_call_return = true;
+            continue;
+        }
+        if arg == "--shared-compare" {
+            options.shared_comparisons = true;
+            continue;
         }
-    } else if let Some(extension) = path.extension() {
-        if extension == "vm" {
-            vm_files.push(path.to_path_buf());
-        } else {
-            println!("un supported file: {:?}", path);
-            return Ok(());
+        if arg == "--tos-cache" {
+            options.cache_top_of_stack = true;
+            continue;
+        }
+        if arg == "--specialize" {
+            options.specialize_push_pop = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .with_context(|| format!("missing value for {}", arg))?;
+        // ブートストラップでA命令に書くので15ビットに収まるアドレスだけ受け付ける
+        let parse_address = || -> Result<u16> {
+            value
+                .parse()
+                .ok()
+                .filter(|&address| address <= MAX_ADDRESS)
+                .with_context(|| format!("invalid address for {}: {}", arg, value))
+        };
+        match arg.as_str() {
+            "--symbols" => symbols = Some(value.clone()),
+            "--source-map" => source_map = Some(value.clone()),
+            "--emit" => {
+                emit = value
+                    .parse()
+                    .with_context(|| format!("expected asm or hack for {}: {}", arg, value))?
+            }
+            "--style" => {
+                options.output_style = value.parse().with_context(|| {
+                    format!(
+                        "expected annotated, plain or stripped for {}: {}",
+                        arg, value
+                    )
+                })?
+            }
+            "--bootstrap" => {
+                options.bootstrap = value
+                    .parse()
+                    .with_context(|| format!("expected on, off or auto for {}: {}", arg, value))?
+            }
+            "--sp" => options.initial_sp = parse_address()?,
+            "--local" => options.segment_bases.local = parse_address()?,
+            "--argument" => options.segment_bases.argument = parse_address()?,
+            "--this" => options.segment_bases.this = parse_address()?,
+            "--that" => options.segment_bases.that = parse_address()?,
+            "--compact-locals" => {
+                options.compact_locals_threshold = Some(
+                    value
+                        .parse()
+                        .with_context(|| format!("invalid count for {}: {}", arg, value))?,
+                )
+            }
+            _ => bail!("unknown option: {}", arg),
         }
     }
 
-    let output_asm_file_name = if is_dir {
-        path.file_name().unwrap().to_string_lossy().to_string()
-    } else {
-        vm_files
-            .get(0)
-            .unwrap()
-            .to_path_buf()
-            .file_stem()
-            .unwrap()
-            .to_string_lossy()
-            .to_string()
+    if paths.len() > 1 && mode != Mode::Link {
+        bail!("unexpected argument: {}", paths[1]);
+    }
+    match mode {
+        Mode::Compile if emit != Emit::Asm || symbols.is_some() || source_map.is_some() => {
+            bail!("--emit, --symbols and --source-map cannot be used with --compile")
+        }
+        Mode::Link if source_map.is_some() => bail!("--source-map cannot be used with --link"),
+        _ => (),
+    }
+    // 翻訳する順番はファイルをまとめて翻訳する場合にしか意味がない
+    if sys_first && mode != Mode::Translate {
+        bail!("--sys-first cannot be used with --compile or --link");
+    }
+    paths.retain(|path| !path.is_empty());
+    if paths.is_empty() {
+        paths.push(current_dir);
+    }
+
+    Ok(Args {
+        mode,
+        paths,
+        sys_first,
+        output,
+        emit,
+        symbols,
+        source_map,
+        options,
+    })
+}
+
+fn vm_translator(args: &Args) -> Result<()> {
+    let path = Path::new(&args.paths[0]);
+    let options = Options {
+        code_writer: args.options.clone(),
+    };
+    let (assembly, optimization) = match args.mode {
+        Mode::Translate => translate_vm_files(args, path, &options)?,
+        Mode::Compile => return compile_vm_files(args, path, &options),
+        Mode::Link => link_object_files(args, &options)?,
     };
-    let output_asm_file_path = path.parent().unwrap().join(format!(
-        "{}.{}",
-        output_asm_file_name, ASSEMBLY_FILE_EXTENSION
-    ));
-
-    let mut code_writer = code_writer::CodeWriter::new(&output_asm_file_path);
-    vm_files.iter().try_for_each(|vm_file: &PathBuf| -> Result<()>{
-        code_writer.set_filename(&vm_file)?;
-        let mut parser = parser::Parser::new(BufReader::new(File::open(vm_file)?));
-        while parser.has_more_lines()? {
-            parser.advance()?;
-
-            match parser.command_type()?.unwrap() {
-                parser::CommandType::Arithmetic => {
-                    code_writer.write_arithmetic(parser.arg1().unwrap().as_str())?;
-                }
-                parser::CommandType::Push | parser::CommandType::Pop => {
-                    code_writer.write_push_pop(
-                        parser.command_type()?.unwrap(),
-                        parser.arg1().unwrap().as_str(),
-                        parser.arg2()?.unwrap(),
-                    )?;
-                }
-                parser::CommandType::Label => {
-                    code_writer.write_label(&parser.arg1().unwrap())?;
-                }
-                parser::CommandType::Goto => {
-                    code_writer.write_goto(&parser.arg1().unwrap())?;
-                }
-                parser::CommandType::If => {
-                    code_writer.write_if(&parser.arg1().unwrap())?;
-                }
-                parser::CommandType::Function => {
-                    let function_name = parser.arg1()?;
-                    let n_vars = parser.arg2()?.unwrap();
-                    code_writer.write_function(&function_name, n_vars)?;
-                }
-                parser::CommandType::Return => {
-                    code_writer.write_return()?;
-                }
-                parser::CommandType::Call => {
-                    let function_name = parser.arg1()?;
-                    let n_args = parser.arg2()?.unwrap();
-                    code_writer.write_call(&function_name, n_args)?;
-                }
-            }
 
-            if !parser.has_more_lines()? {
-                break;
-            }
-            code_writer.increment_uniq_index();
+    if let Some(report) = optimization {
+        // 標準出力に書き込むアセンブリと混ざらないよう標準エラー出力に出す
+        eprintln!("Optimized: {}", report);
+    }
+
+    if let Some(symbols) = &args.symbols {
+        let program = assembler::assemble(&assembly)?;
+        fs::write(symbols, program.symbols.to_json())?;
+    }
+
+    let output_content = args.emit.render(assembly)?;
+    match args.output.as_deref() {
+        Some(STDOUT_OUTPUT) => io::stdout().write_all(output_content.as_bytes())?,
+        output => {
+            let output_file_path = match output {
+                Some(output) => PathBuf::from(output),
+                None => default_output_path(path)?.with_extension(args.emit.extension()),
+            };
+            fs::write(&output_file_path, output_content)?;
+            println!("Translated: {}", output_file_path.to_string_lossy());
         }
-        Ok(())
-    })?;
+    }
 
-    code_writer.close()?;
-    println!("Translated: {}", &output_asm_file_path.to_string_lossy());
+    Ok(())
+}
+
+fn translate_vm_files(
+    args: &Args,
+    path: &Path,
+    options: &Options,
+) -> Result<(String, Option<OptimizeReport>)> {
+    let inputs = collect_vm_files(path, args.sys_first)?
+        .iter()
+        .map(|vm_file| Input::read(vm_file))
+        .collect::<Result<Vec<_>>>()?;
+    let translation = translate(&inputs, options)?;
+    let Some(assembly) = translation.assembly else {
+        bail!(join_lines(&translation.diagnostics));
+    };
+    if let (Some(path), Some(source_map)) = (&args.source_map, &translation.source_map) {
+        fs::write(path, source_map.to_json())?;
+    }
+    Ok((assembly, translation.optimization))
+}
 
+/// vmファイルごとにオブジェクトを書き出す。-oを指定しない場合は拡張子をvmoに変えたパスに出力する
+fn compile_vm_files(args: &Args, path: &Path, options: &Options) -> Result<()> {
+    let vm_files = collect_vm_files(path, false)?;
+    if args.output.is_some() && vm_files.len() > 1 {
+        bail!("-o cannot be used when compiling multiple .vm files");
+    }
+    // 1つでもエラーがあればどのオブジェクトも書き出さない
+    let mut objects = Vec::new();
+    let mut diagnostics = Vec::new();
+    for vm_file in &vm_files {
+        let compilation = compile(&Input::read(vm_file)?, options)?;
+        objects.extend(compilation.object);
+        diagnostics.extend(compilation.diagnostics);
+    }
+    if !diagnostics.is_empty() {
+        bail!(join_lines(&diagnostics));
+    }
+
+    for (vm_file, object) in vm_files.iter().zip(objects) {
+        match args.output.as_deref() {
+            Some(STDOUT_OUTPUT) => io::stdout().write_all(object.to_string().as_bytes())?,
+            output => {
+                let object_file_path = match output {
+                    Some(output) => PathBuf::from(output),
+                    None => vm_file.with_extension(OBJECT_FILE_EXTENSION),
+                };
+                fs::write(&object_file_path, object.to_string())?;
+                println!("Compiled: {}", object_file_path.to_string_lossy());
+            }
+        }
+    }
     Ok(())
 }
 
+/// 指定された順にオブジェクトを読み込んでリンクする。ディレクトリ内のオブジェクトは名前順に並べる
+fn link_object_files(args: &Args, options: &Options) -> Result<(String, Option<OptimizeReport>)> {
+    let mut objects = Vec::new();
+    for path in &args.paths {
+        for object_file in collect_object_files(Path::new(path))? {
+            let object = fs::read_to_string(&object_file)
+                .with_context(|| format!("failed to read {:?}", object_file))?
+                .parse::<Object>()
+                .with_context(|| format!("invalid object file {:?}", object_file))?;
+            objects.push(object);
+        }
+    }
+    let linking = link(&objects, options)?;
+    let Some(assembly) = linking.assembly else {
+        bail!(join_lines(&linking.errors));
+    };
+    Ok((assembly, linking.optimization))
+}
+
+fn join_lines<T: ToString>(errors: &[T]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
+    use vmtranslator::{Bootstrap, CodeWriterOptions, Emit, OutputStyle, SegmentBases};
 
-    use crate::{parse_arg, vm_translator};
+    use std::{fs, path::PathBuf};
+
+    use crate::{parse_arg, vm_translator, Mode};
 
     #[test]
     fn parse_test() -> Result<()> {
         let expect = "./";
         let args = vec!["".to_string(), "".to_string()];
-        assert_eq!(parse_arg(args)?, expect.to_string());
+        assert_eq!(parse_arg(args)?.paths, [expect]);
 
         let expect = "test_vm_files/8/FunctionCalls/FibonacciElement";
         let args = vec!["".to_string(), expect.to_string()];
-        assert_eq!(parse_arg(args)?, expect.to_string());
+        assert_eq!(parse_arg(args)?.paths, [expect]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_options_test() -> Result<()> {
+        let args = [
+            "",
+            "--bootstrap",
+            "off",
+            "--sp",
+            "261",
+            "--this",
+            "3000",
+            "--raw-labels",
+            "--opt",
+            "--shared-calls",
+            "--shared-compare",
+            "--tos-cache",
+            "--specialize",
+            "--compact-locals",
+            "4",
+            "--style",
+            "stripped",
+            "Main.vm",
+        ]
+        .map(str::to_string)
+        .to_vec();
+        let args = parse_arg(args)?;
+        assert_eq!(args.paths, ["Main.vm"]);
+        assert_eq!(args.mode, Mode::Translate);
+        assert_eq!(args.emit, Emit::Asm);
+        assert_eq!(
+            args.options,
+            CodeWriterOptions {
+                raw_labels_outside_function: true,
+                bootstrap: Bootstrap::Off,
+                initial_sp: 261,
+                segment_bases: SegmentBases {
+                    this: 3000,
+                    ..Default::default()
+                },
+                optimize: true,
+                shared_call_return: true,
+                shared_comparisons: true,
+                cache_top_of_stack: true,
+                specialize_push_pop: true,
+                compact_locals_threshold: Some(4),
+                output_style: OutputStyle::Stripped,
+            }
+        );
+
+        for args in [
+            vec!["", "--bootstrap", "maybe"],
+            vec!["", "--emit", "bin"],
+            vec!["", "--style", "verbose"],
+            vec!["", "--sp"],
+            vec!["", "--sp", "40000"],
+            vec!["", "--compact-locals", "-1"],
+            vec!["", "--unknown", "1"],
+            vec!["", "a.vm", "b.vm"],
+            vec!["", "--compile", "--link"],
+            vec!["", "--compile", "--emit", "hack"],
+            vec!["", "--link", "--source-map", "map.json"],
+        ] {
+            assert!(parse_arg(args.into_iter().map(str::to_string).collect()).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_mode_test() -> Result<()> {
+        let args = parse_arg(["", "--compile", "Main.vm"].map(str::to_string).to_vec())?;
+        assert_eq!(args.mode, Mode::Compile);
 
+        let args = ["", "--link", "build", "os/Math.vmo", "-o", "Prog.asm"]
+            .map(str::to_string)
+            .to_vec();
+        let args = parse_arg(args)?;
+        assert_eq!(args.mode, Mode::Link);
+        assert_eq!(args.paths, ["build", "os/Math.vmo"]);
+
+        for mode in ["--compile", "--link"] {
+            let args = ["", mode, "--sys-first", "build"]
+                .map(str::to_string)
+                .to_vec();
+            assert_eq!(
+                parse_arg(args).unwrap_err().to_string(),
+                "--sys-first cannot be used with --compile or --link"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_output_test() -> Result<()> {
+        let args = ["", "-o", "-", "Main.vm"].map(str::to_string).to_vec();
+        assert_eq!(parse_arg(args)?.output, Some("-".to_string()));
+
+        let args = ["", "Main.vm", "--output", "out/Prog.asm"]
+            .map(str::to_string)
+            .to_vec();
+        assert_eq!(parse_arg(args)?.output, Some("out/Prog.asm".to_string()));
+
+        assert!(parse_arg(["", "-o"].map(str::to_string).to_vec()).is_err());
         Ok(())
     }
 
+    /// テストの出力先。リポジトリ内のファイルを書き換えないよう一時ディレクトリに作る
+    fn temp_output_dir(name: &str) -> Result<PathBuf> {
+        let dir =
+            std::env::temp_dir().join(format!("vm_translator_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
     #[test]
     fn run_translator() -> Result<()> {
-        let args = vec![
-            "".to_string(),
-            "test_vm_files/8/FunctionCalls/FibonacciElement".to_string(),
-        ];
-        vm_translator(&parse_arg(args)?)?;
+        let input = "test_vm_files/8/FunctionCalls/FibonacciElement";
+        let dir = temp_output_dir("run_translator")?;
+        let output = dir
+            .join("FibonacciElement.asm")
+            .to_string_lossy()
+            .to_string();
+        let args = ["", input, "-o", &output].map(str::to_string).to_vec();
+        vm_translator(&parse_arg(args.clone())?)?;
+        let first = fs::read(&output)?;
+
+        // 同じ入力からは常にバイト単位で同じ出力になる
+        vm_translator(&parse_arg(args.clone())?)?;
+        assert_eq!(first, fs::read(&output)?);
+
+        let hack = output.replace(".asm", ".hack");
+        let hack_args = ["", input, "--emit", "hack", "-o", &hack]
+            .map(str::to_string)
+            .to_vec();
+        vm_translator(&parse_arg(hack_args)?)?;
+        let hack = fs::read_to_string(hack)?;
+        assert!(hack.lines().all(|line| line.len() == 16));
+
+        let symbols = output.replace(".asm", ".sym.json");
+        let symbols_args = [args.clone(), vec!["--symbols".to_string(), symbols.clone()]].concat();
+        vm_translator(&parse_arg(symbols_args)?)?;
+        let symbols = fs::read_to_string(symbols)?;
+        assert!(symbols.contains("{\"name\": \"Main.fibonacci\", \"rom\": "));
+        assert!(symbols.contains("{\"name\": \"Main$ret$1\", \"rom\": "));
+
+        let source_map = output.replace(".asm", ".map.json");
+        let source_map_args = [args, vec!["--source-map".to_string(), source_map.clone()]].concat();
+        vm_translator(&parse_arg(source_map_args)?)?;
+        let source_map = fs::read_to_string(source_map)?;
+        assert!(source_map.contains("\"line\": 25, \"command\": \"call Main.fibonacci 1\"}"));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn run_compile_and_link() -> Result<()> {
+        let input = "test_vm_files/8/FunctionCalls/FibonacciElement";
+        let dir = temp_output_dir("run_compile_and_link")?;
+        let object_path = |module: &str| {
+            dir.join(format!("{}.vmo", module))
+                .to_string_lossy()
+                .to_string()
+        };
+        for module in ["Sys", "Main"] {
+            let compile_args = [
+                "",
+                "--compile",
+                &format!("{}/{}.vm", input, module),
+                "-o",
+                &object_path(module),
+            ]
+            .map(str::to_string)
+            .to_vec();
+            vm_translator(&parse_arg(compile_args)?)?;
+        }
+        let object = fs::read_to_string(object_path("Sys"))?;
+        assert!(object.starts_with(".object Sys\n.export Sys.init\n"));
+
+        let output = dir.join("Linked.hack").to_string_lossy().to_string();
+        let link_args = [
+            "",
+            "--link",
+            &dir.to_string_lossy(),
+            "--emit",
+            "hack",
+            "-o",
+            &output,
+        ]
+        .map(str::to_string)
+        .to_vec();
+        vm_translator(&parse_arg(link_args)?)?;
+        assert!(fs::read_to_string(&output)?
+            .lines()
+            .all(|line| line.len() == 16));
+
+        // Main.vmoが無いのでSys.initから呼ぶ関数が見つからない
+        let link_args = ["", "--link", &object_path("Sys")]
+            .map(str::to_string)
+            .to_vec();
+        assert_eq!(
+            vm_translator(&parse_arg(link_args)?)
+                .unwrap_err()
+                .to_string(),
+            "undefined function `Main.fibonacci` called from Sys"
+        );
 
+        fs::remove_dir_all(dir)?;
         Ok(())
     }
 }